@@ -1,11 +1,115 @@
+//! Handshake and transport benchmarks. Keys come from a counting RNG, so each run does the
+//! same work. Run `cargo bench --features ring-resolver` to also measure ring's primitives
+//! next to the pure-Rust ones.
+
+#![allow(deprecated)]
+
 #[macro_use]
 extern crate criterion;
 
 use criterion::{Benchmark, Criterion, Throughput};
-use snow::{params::*, *};
+use rand_core::{impls, CryptoRng, RngCore};
+#[cfg(feature = "ring-resolver")]
+use snow::resolvers::{FallbackResolver, RingResolver};
+use snow::{
+    params::*,
+    resolvers::{BoxedCryptoResolver, CryptoResolver, DefaultResolver},
+    types::*,
+    *,
+};
 
 const MSG_SIZE: usize = 4096;
 
+/// Counts up from a seed, so every run generates the same keys.
+struct BenchRng(u64);
+
+impl RngCore for BenchRng {
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 += 1;
+        self.0
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        impls::fill_bytes_via_next(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for BenchRng {}
+impl Random for BenchRng {}
+
+/// Wraps a resolver to hand out a [`BenchRng`] instead of a real one.
+struct BenchResolver {
+    inner: BoxedCryptoResolver,
+    seed:  u64,
+}
+
+impl CryptoResolver for BenchResolver {
+    fn resolve_rng(&self) -> Option<Box<dyn Random>> {
+        Some(Box::new(BenchRng(self.seed)))
+    }
+
+    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<dyn Dh>> {
+        self.inner.resolve_dh(choice)
+    }
+
+    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<dyn Hash>> {
+        self.inner.resolve_hash(choice)
+    }
+
+    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<dyn Cipher>> {
+        self.inner.resolve_cipher(choice)
+    }
+}
+
+type MakeResolver = fn() -> BoxedCryptoResolver;
+
+/// The resolvers to compare: pure Rust, and ring where it has the primitive.
+fn resolvers() -> Vec<(&'static str, MakeResolver)> {
+    #[allow(unused_mut)]
+    let mut resolvers: Vec<(&'static str, MakeResolver)> =
+        vec![("default", || Box::new(DefaultResolver))];
+    #[cfg(feature = "ring-resolver")]
+    resolvers.push(("ring", || {
+        Box::new(FallbackResolver::new(Box::new(RingResolver), Box::new(DefaultResolver)))
+    }));
+    resolvers
+}
+
+fn builder(params: &NoiseParams, resolver: MakeResolver, seed: u64) -> Builder<'static> {
+    Builder::with_resolver(params.clone(), Box::new(BenchResolver { inner: resolver(), seed }))
+}
+
+/// Runs the handshake to completion with empty payloads.
+fn handshake(h_i: &mut HandshakeState, h_r: &mut HandshakeState) {
+    let mut buffer_msg = [0u8; 1024];
+    let mut buffer_out = [0u8; 1024];
+    let (mut writer, mut reader) = (h_i, h_r);
+    while !writer.is_handshake_finished() {
+        let len = writer.write_message(&[], &mut buffer_msg).unwrap();
+        reader.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+        std::mem::swap(&mut writer, &mut reader);
+    }
+}
+
+fn transport_pair(
+    params: &NoiseParams,
+    resolver: MakeResolver,
+) -> (TransportState, TransportState) {
+    let mut h_i = builder(params, resolver, 0).build_initiator().unwrap();
+    let mut h_r = builder(params, resolver, 1000).build_responder().unwrap();
+    handshake(&mut h_i, &mut h_r);
+    (h_i.into_transport_mode().unwrap(), h_r.into_transport_mode().unwrap())
+}
+
 fn benchmarks(c: &mut Criterion) {
     c.bench(
         "builder",
@@ -32,111 +136,85 @@ fn benchmarks(c: &mut Criterion) {
         .throughput(Throughput::Elements(1)),
     );
 
-    c.bench(
-        "handshake",
-        Benchmark::new("xx", |b| {
-            b.iter(move || {
-                let pattern: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2b".parse().unwrap();
-                let mut h_i = Builder::new(pattern.clone())
-                    .local_private_key(&[1u8; 32])
-                    .build_initiator()
-                    .unwrap();
-                let mut h_r =
-                    Builder::new(pattern).local_private_key(&[2u8; 32]).build_responder().unwrap();
-
-                let mut buffer_msg = [0u8; MSG_SIZE * 2];
-                let mut buffer_out = [0u8; MSG_SIZE * 2];
-
-                // get the handshaking out of the way for even testing
-                let len = h_i.write_message(&[0u8; 0], &mut buffer_msg).unwrap();
-                h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
-                let len = h_r.write_message(&[0u8; 0], &mut buffer_msg).unwrap();
-                h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
-                let len = h_i.write_message(&[0u8; 0], &mut buffer_msg).unwrap();
-                h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
-            })
-        })
-        .throughput(Throughput::Elements(1)),
-    );
-
-    c.bench(
-        "handshake",
-        Benchmark::new("nn", |b| {
-            b.iter(move || {
-                let pattern = "Noise_NN_25519_ChaChaPoly_BLAKE2b";
-                let mut h_i = Builder::new(pattern.parse().unwrap()).build_initiator().unwrap();
-                let mut h_r = Builder::new(pattern.parse().unwrap()).build_responder().unwrap();
-
-                let mut buffer_msg = [0u8; MSG_SIZE * 2];
-                let mut buffer_out = [0u8; MSG_SIZE * 2];
+    // Full handshakes, including building both sides.
+    for pattern in &["NN", "XX", "IK"] {
+        let params: NoiseParams =
+            format!("Noise_{}_25519_ChaChaPoly_BLAKE2s", pattern).parse().unwrap();
+        for (resolver_name, resolver) in resolvers() {
+            let params = params.clone();
+            let r_keypair = builder(&params, resolver, 2000).generate_keypair().unwrap();
+            c.bench(
+                "handshake",
+                Benchmark::new(format!("{}/{}", params.name, resolver_name), move |b| {
+                    let pattern = params.handshake.pattern;
+                    b.iter(|| {
+                        let mut b_i =
+                            builder(&params, resolver, 0).remote_public_key(&r_keypair.public);
+                        if pattern.needs_local_static_key(true) {
+                            b_i = b_i.local_private_key(&[1u8; 32]);
+                        }
+                        let mut b_r = builder(&params, resolver, 1000);
+                        if pattern.needs_local_static_key(false) {
+                            b_r = b_r.local_private_key(&r_keypair.private);
+                        }
+                        let mut h_i = b_i.build_initiator().unwrap();
+                        let mut h_r = b_r.build_responder().unwrap();
+                        handshake(&mut h_i, &mut h_r);
+                    })
+                })
+                .throughput(Throughput::Elements(1)),
+            );
+        }
+    }
 
-                // get the handshaking out of the way for even testing
-                let len = h_i.write_message(&[0u8; 0], &mut buffer_msg).unwrap();
-                h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
-                let len = h_r.write_message(&[0u8; 0], &mut buffer_msg).unwrap();
-                h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
-            })
-        })
-        .throughput(Throughput::Elements(1)),
-    );
+    // One encrypted and decrypted message of MSG_SIZE bytes per iteration.
+    #[allow(unused_mut)]
+    let mut ciphers = vec!["ChaChaPoly", "AESGCM"];
+    #[cfg(feature = "aes128gcm")]
+    ciphers.push("AES128GCM");
+    #[cfg(feature = "xchachapoly")]
+    ciphers.push("XChaChaPoly");
+    for cipher in ciphers {
+        let params: NoiseParams = format!("Noise_NN_25519_{}_SHA256", cipher).parse().unwrap();
+        for (resolver_name, resolver) in resolvers() {
+            let (mut h_i, mut h_r) = transport_pair(&params, resolver);
+            c.bench(
+                "transport",
+                Benchmark::new(format!("{}/{}", cipher, resolver_name), move |b| {
+                    let mut buffer_msg = [0u8; MSG_SIZE * 2];
+                    let mut buffer_out = [0u8; MSG_SIZE * 2];
+                    b.iter(|| {
+                        let len =
+                            h_i.write_message(&buffer_msg[..MSG_SIZE], &mut buffer_out).unwrap();
+                        h_r.read_message(&buffer_out[..len], &mut buffer_msg).unwrap();
+                    })
+                })
+                .throughput(Throughput::Bytes(MSG_SIZE as u64 * 2)),
+            );
+        }
+    }
 
-    if cfg!(feature = "ring-accelerated") {
+    // Writes only, to compare the two-buffer and in-place APIs.
+    for &in_place in &[false, true] {
+        let name = if in_place { "write_in_place" } else { "write" };
+        let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+        let (mut h_i, _) = transport_pair(&params, || Box::new(DefaultResolver));
         c.bench(
             "transport",
-            Benchmark::new("AESGCM_SHA256 throughput", |b| {
-                static PATTERN: &str = "Noise_NN_25519_AESGCM_SHA256";
-
-                let mut h_i = Builder::new(PATTERN.parse().unwrap()).build_initiator().unwrap();
-                let mut h_r = Builder::new(PATTERN.parse().unwrap()).build_responder().unwrap();
-
+            Benchmark::new(name, move |b| {
                 let mut buffer_msg = [0u8; MSG_SIZE * 2];
                 let mut buffer_out = [0u8; MSG_SIZE * 2];
-
-                // get the handshaking out of the way for even testing
-                let len = h_i.write_message(&[0u8; 0], &mut buffer_msg).unwrap();
-                h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
-                let len = h_r.write_message(&[0u8; 0], &mut buffer_msg).unwrap();
-                h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
-
-                let mut h_i = h_i.into_transport_mode().unwrap();
-                let mut h_r = h_r.into_transport_mode().unwrap();
-
-                b.iter(move || {
-                    let len = h_i.write_message(&buffer_msg[..MSG_SIZE], &mut buffer_out).unwrap();
-                    let _ = h_r.read_message(&buffer_out[..len], &mut buffer_msg).unwrap();
+                b.iter(|| {
+                    if in_place {
+                        h_i.write_message_in_place(&mut buffer_msg, MSG_SIZE).unwrap();
+                    } else {
+                        h_i.write_message(&buffer_msg[..MSG_SIZE], &mut buffer_out).unwrap();
+                    }
                 })
             })
-            .throughput(Throughput::Bytes(MSG_SIZE as u64 * 2)),
+            .throughput(Throughput::Bytes(MSG_SIZE as u64)),
         );
     }
-
-    c.bench(
-        "transport",
-        Benchmark::new("ChaChaPoly_BLAKE2s throughput", |b| {
-            static PATTERN: &str = "Noise_NN_25519_ChaChaPoly_BLAKE2s";
-
-            let mut h_i = Builder::new(PATTERN.parse().unwrap()).build_initiator().unwrap();
-            let mut h_r = Builder::new(PATTERN.parse().unwrap()).build_responder().unwrap();
-
-            let mut buffer_msg = [0u8; MSG_SIZE * 2];
-            let mut buffer_out = [0u8; MSG_SIZE * 2];
-
-            // get the handshaking out of the way for even testing
-            let len = h_i.write_message(&[0u8; 0], &mut buffer_msg).unwrap();
-            h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
-            let len = h_r.write_message(&[0u8; 0], &mut buffer_msg).unwrap();
-            h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
-
-            let mut h_i = h_i.into_transport_mode().unwrap();
-            let mut h_r = h_r.into_transport_mode().unwrap();
-
-            b.iter(move || {
-                let len = h_i.write_message(&buffer_msg[..MSG_SIZE], &mut buffer_out).unwrap();
-                let _ = h_r.read_message(&buffer_out[..len], &mut buffer_msg).unwrap();
-            })
-        })
-        .throughput(Throughput::Bytes(MSG_SIZE as u64 * 2)),
-    );
 }
 
 criterion_group!(benches, benchmarks);
@@ -2,12 +2,14 @@
 set -e
 TARGET="$([ -n "$1" ] && echo "--target $1" || echo "")"
 
-COMMON_FEATURES="xchachapoly vector-tests"
+COMMON_FEATURES="xchachapoly aes128gcm blake3 sha3 test-utils tokio vector-tests logging elligator2"
 
 set -x
 cargo check --benches
 cargo test $TARGET --no-default-features
+cargo test $TARGET --no-default-features --features default-resolver
 cargo test $TARGET --features "$COMMON_FEATURES"
+cargo test $TARGET --lib --features "aes128gcm aes-gcm/force-soft" aes
 cargo test $TARGET --features "ring-resolver $COMMON_FEATURES"
 cargo test $TARGET --features "ring-accelerated $COMMON_FEATURES"
 cargo test $TARGET --features "hfs pqclean_kyber1024 $COMMON_FEATURES"
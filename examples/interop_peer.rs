@@ -0,0 +1,74 @@
+#![cfg_attr(
+    not(any(feature = "default-resolver", feature = "ring-accelerated",)),
+    allow(dead_code, unused_extern_crates, unused_imports)
+)]
+//! The responder side of the interop harness in `tests/interop.rs`, speaking over stdin and
+//! stdout. It serves as the reference for what a peer wrapping another implementation has
+//! to do, and lets the harness be checked against snow itself; it proves nothing about
+//! compatibility with other implementations.
+//!
+//! # Usage
+//! `cargo run --example interop_peer -- Noise_XX_25519_ChaChaPoly_BLAKE2s`, or point the test
+//! at the built binary:
+//!
+//! ```text
+//! cargo build --example interop_peer
+//! SNOW_INTEROP_PEER=target/debug/examples/interop_peer \
+//!     cargo test --features interop-tests --test interop
+//! ```
+//!
+//! Every message is preceded by its length as a 16-bit big-endian integer. After the
+//! handshake, every transport message received is answered with one carrying the same
+//! payload. The peer exits once its input is closed.
+
+use snow::{params::NoiseParams, Builder};
+use std::io::{self, Read, Write};
+
+#[cfg(any(feature = "default-resolver", feature = "ring-accelerated"))]
+fn main() {
+    let name = std::env::args().nth(1).expect("usage: interop_peer <protocol name>");
+    let params: NoiseParams = name.parse().unwrap();
+    let (mut input, mut output) = (io::stdin().lock(), io::stdout().lock());
+    let mut buf = vec![0u8; 65535];
+
+    let builder = Builder::new(params);
+    let static_key = builder.generate_keypair().unwrap().private;
+    let mut noise = builder.local_private_key(&static_key).build_responder().unwrap();
+    while !noise.is_handshake_finished() {
+        if noise.is_my_turn() {
+            let len = noise.write_message(&[], &mut buf).unwrap();
+            send(&mut output, &buf[..len]);
+        } else {
+            noise.read_message(&recv(&mut input).unwrap(), &mut buf).unwrap();
+        }
+    }
+
+    let mut noise = noise.into_transport_mode().unwrap();
+    let mut payload = vec![0u8; 65535];
+    while let Ok(msg) = recv(&mut input) {
+        let len = noise.read_message(&msg, &mut payload).unwrap();
+        let len = noise.write_message(&payload[..len], &mut buf).unwrap();
+        send(&mut output, &buf[..len]);
+    }
+}
+
+/// 16-bit BE size followed by payload.
+fn recv(input: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut msg_len_buf = [0u8; 2];
+    input.read_exact(&mut msg_len_buf)?;
+    let mut msg = vec![0u8; u16::from_be_bytes(msg_len_buf).into()];
+    input.read_exact(&mut msg[..])?;
+    Ok(msg)
+}
+
+/// 16-bit BE size followed by payload.
+fn send(output: &mut impl Write, buf: &[u8]) {
+    output.write_all(&(buf.len() as u16).to_be_bytes()).unwrap();
+    output.write_all(buf).unwrap();
+    output.flush().unwrap();
+}
+
+#[cfg(not(any(feature = "default-resolver", feature = "ring-accelerated")))]
+fn main() {
+    panic!("Example must be compiled with some cryptographic provider.");
+}
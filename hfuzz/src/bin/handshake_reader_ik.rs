@@ -0,0 +1,33 @@
+//! Feeds arbitrary first messages to an IK responder with a fixed static key, so that inputs
+//! can get past the `es` and `ss` tokens into the static key and payload decryption.
+//!
+//! Seed it with the valid messages in `corpus/handshake_reader_ik`:
+//!
+//! ```sh
+//! HFUZZ_INPUT=corpus/handshake_reader_ik cargo hfuzz run handshake_reader_ik
+//! ```
+
+#[macro_use] extern crate honggfuzz;
+#[macro_use] extern crate lazy_static;
+extern crate snow;
+
+use snow::params::NoiseParams;
+
+static RESPONDER_KEY: &'static [u8] = b"i don't care for fidget spinners";
+lazy_static! {
+    static ref PARAMS: NoiseParams = "Noise_IK_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+}
+
+fn main() {
+    let mut out_buf = vec![0u8; 128 * 1024 * 1024];
+    loop {
+        fuzz!(|data: &[u8]| {
+            let builder = snow::Builder::new(PARAMS.clone()).local_private_key(RESPONDER_KEY);
+            let mut noise = builder.build_responder().unwrap();
+
+            if noise.read_message(data, &mut out_buf).is_ok() {
+                let _ = noise.write_message(&[], &mut out_buf);
+            }
+        });
+    }
+}
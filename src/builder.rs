@@ -1,14 +1,17 @@
-#[cfg(feature = "hfs")]
-use crate::params::HandshakeModifier;
+use alloc::{boxed::Box, vec, vec::Vec};
 use crate::{
     cipherstate::{CipherState, CipherStates},
-    constants::{MAXDHLEN, PSKLEN},
+    constants::{MAXAEADMSGLEN, MAXDHLEN, MAXMSGLEN, PSKLEN},
     error::{Error, InitStage, Prerequisite},
-    handshakestate::HandshakeState,
-    params::NoiseParams,
-    resolvers::{BoxedCryptoResolver, CryptoResolver},
+    handshakestate::{HandshakeState, StepHook},
+    params::{HandshakeModifier, NoiseParams, Policy},
+    resolvers::{BoxedCryptoResolver, CryptoResolver, DhRole},
+    transportstate::TransportState,
+    types::{Hash, Random},
     utils::Toggle,
 };
+#[cfg(feature = "std")]
+use std::io;
 use subtle::ConstantTimeEq;
 
 /// A keypair object returned by [`Builder::generate_keypair()`]
@@ -39,7 +42,7 @@ impl PartialEq for Keypair {
 /// # use snow::Builder;
 /// # let my_long_term_key = [0u8; 32];
 /// # let their_pub_key = [0u8; 32];
-/// # #[cfg(any(feature = "default-resolver", feature = "ring-accelerated"))]
+/// # #[cfg(all(feature = "default-resolver", feature = "std"))]
 /// let noise = Builder::new("Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap())
 ///     .local_private_key(&my_long_term_key)
 ///     .remote_public_key(&their_pub_key)
@@ -48,86 +51,357 @@ impl PartialEq for Keypair {
 ///     .unwrap();
 /// ```
 pub struct Builder<'builder> {
-    params:   NoiseParams,
-    resolver: BoxedCryptoResolver,
-    s:        Option<&'builder [u8]>,
-    e_fixed:  Option<&'builder [u8]>,
-    rs:       Option<&'builder [u8]>,
-    psks:     [Option<&'builder [u8]>; 10],
-    plog:     Option<&'builder [u8]>,
+    params:      NoiseParams,
+    resolver:    BoxedCryptoResolver,
+    s:           Option<&'builder [u8]>,
+    s_pub:       Option<&'builder [u8]>,
+    e_fixed:     Option<&'builder [u8]>,
+    e_eager:     bool,
+    hkdf_ctx:    Option<&'builder [u8]>,
+    rs:          Option<&'builder [u8]>,
+    psks:        [Option<&'builder [u8]>; 10],
+    psk_places:  Option<&'builder [u8]>,
+    plog:        Vec<&'builder [u8]>,
+    #[cfg(feature = "std")]
+    plog_reader: Option<Box<dyn io::Read + Send + 'builder>>,
+    policy:      Option<Policy>,
+    rng:         Option<Box<dyn Random>>,
+    max_len:     usize,
+    on_step:     Option<StepHook>,
+}
+
+/// The default crypto resolver.
+#[cfg(all(
+    feature = "default-resolver",
+    not(any(feature = "ring-accelerated", feature = "libsodium-accelerated"))
+))]
+fn default_resolver() -> BoxedCryptoResolver {
+    use crate::resolvers::DefaultResolver;
+
+    Box::new(DefaultResolver)
+}
+
+/// The ring resolver with the default resolver as a fallback.
+#[cfg(all(not(feature = "libsodium-accelerated"), feature = "ring-accelerated"))]
+fn default_resolver() -> BoxedCryptoResolver {
+    use crate::resolvers::{DefaultResolver, FallbackResolver, RingResolver};
+
+    Box::new(FallbackResolver::new(Box::new(RingResolver), Box::new(DefaultResolver)))
+}
+
+/// The libsodium resolver with the default resolver as a fallback.
+#[cfg(all(not(feature = "ring-accelerated"), feature = "libsodium-accelerated"))]
+fn default_resolver() -> BoxedCryptoResolver {
+    use crate::resolvers::{DefaultResolver, FallbackResolver, SodiumResolver};
+
+    Box::new(FallbackResolver::new(Box::new(SodiumResolver), Box::new(DefaultResolver)))
+}
+
+/// Every protocol name that [`Builder::new()`] can build with the compiled-in features and
+/// resolver, e.g. to advertise them for negotiation.
+///
+/// This covers each handshake pattern without modifiers, combined with every DH, cipher and
+/// hash the resolver provides, in the form `Noise_XX_25519_ChaChaPoly_BLAKE2s`.
+#[cfg(all(
+    feature = "default-resolver",
+    not(all(feature = "ring-accelerated", feature = "libsodium-accelerated"))
+))]
+pub fn supported_protocols() -> Vec<alloc::string::String> {
+    use crate::params::{
+        SUPPORTED_CIPHER_CHOICES, SUPPORTED_DH_CHOICES, SUPPORTED_HANDSHAKE_PATTERNS,
+        SUPPORTED_HASH_CHOICES,
+    };
+    use alloc::format;
+
+    let resolver = default_resolver();
+    let dhs: Vec<_> =
+        SUPPORTED_DH_CHOICES.iter().filter(|dh| resolver.resolve_dh(dh).is_some()).collect();
+    let ciphers: Vec<_> = SUPPORTED_CIPHER_CHOICES
+        .iter()
+        .filter(|cipher| resolver.resolve_cipher(cipher).is_some())
+        .collect();
+    let hashes: Vec<_> = SUPPORTED_HASH_CHOICES
+        .iter()
+        .filter(|hash| resolver.resolve_hash(hash).is_some())
+        .collect();
+
+    let mut names = vec![];
+    for pattern in SUPPORTED_HANDSHAKE_PATTERNS {
+        for dh in &dhs {
+            for cipher in &ciphers {
+                for hash in &hashes {
+                    names.push(format!(
+                        "Noise_{}_{}_{}_{}",
+                        pattern.as_str(),
+                        dh.as_str(),
+                        cipher.as_str(),
+                        hash.as_str()
+                    ));
+                }
+            }
+        }
+    }
+    names
 }
 
 impl<'builder> Builder<'builder> {
-    /// Create a Builder with the default crypto resolver.
+    /// Create a Builder with the default crypto resolver. With the `ring-accelerated` or
+    /// `libsodium-accelerated` feature, it prefers that library's primitives and falls back to
+    /// the default resolver for the rest.
     #[cfg(all(
         feature = "default-resolver",
-        not(any(feature = "ring-accelerated", feature = "libsodium-accelerated"))
+        not(all(feature = "ring-accelerated", feature = "libsodium-accelerated"))
     ))]
     pub fn new(params: NoiseParams) -> Self {
-        use crate::resolvers::DefaultResolver;
-
-        Self::with_resolver(params, Box::new(DefaultResolver::default()))
+        Self::with_resolver(params, default_resolver())
     }
 
-    /// Create a Builder with the ring resolver and default resolver as a fallback.
-    #[cfg(all(not(feature = "libsodium-accelerated"), feature = "ring-accelerated"))]
-    pub fn new(params: NoiseParams) -> Self {
-        use crate::resolvers::{DefaultResolver, FallbackResolver, RingResolver};
+    /// Create a Builder whose RNG is deterministically derived from `seed`, falling back to
+    /// the same resolver as [`Builder::new()`] for everything else.
+    ///
+    /// Handshakes built this way produce byte-identical messages on every run, which is
+    /// handy for comparing against test vectors. Use a different seed for each peer.
+    ///
+    /// **The seeded RNG is not cryptographically secure. Never use it outside of tests.**
+    #[cfg(all(feature = "test-utils", feature = "default-resolver"))]
+    pub fn with_seed(params: NoiseParams, seed: [u8; 32]) -> Self {
+        use crate::resolvers::{FallbackResolver, SeededResolver};
+
+        let mut builder = Self::new(params);
+        builder.resolver =
+            Box::new(FallbackResolver::new(Box::new(SeededResolver::new(seed)), builder.resolver));
+        builder
+    }
 
-        Self::with_resolver(
+    /// Create a Builder with a custom crypto resolver.
+    pub fn with_resolver(params: NoiseParams, resolver: BoxedCryptoResolver) -> Self {
+        Builder {
             params,
-            Box::new(FallbackResolver::new(Box::new(RingResolver), Box::new(DefaultResolver))),
-        )
+            resolver,
+            s: None,
+            s_pub: None,
+            e_fixed: None,
+            e_eager: false,
+            hkdf_ctx: None,
+            rs: None,
+            plog: vec![],
+            #[cfg(feature = "std")]
+            plog_reader: None,
+            psks: [None; 10],
+            psk_places: None,
+            policy: None,
+            rng: None,
+            max_len: MAXMSGLEN,
+            on_step: None,
+        }
     }
 
-    /// Create a Builder with the ring resolver and default resolver as a fallback.
-    #[cfg(all(not(feature = "ring-accelerated"), feature = "libsodium-accelerated"))]
-    pub fn new(params: NoiseParams) -> Self {
-        use crate::resolvers::{DefaultResolver, FallbackResolver, SodiumResolver};
+    /// Use `rng` instead of the resolver's RNG, keeping the resolver for everything else.
+    ///
+    /// The RNG is only used to generate ephemeral keys, so a key given to
+    /// `fixed_ephemeral_key_for_testing_only()` takes precedence over it.
+    /// [`generate_keypair()`](#method.generate_keypair) still uses the resolver's RNG.
+    pub fn rng(mut self, rng: Box<dyn Random>) -> Self {
+        self.rng = Some(rng);
+        self
+    }
 
-        Self::with_resolver(
-            params,
-            Box::new(FallbackResolver::new(Box::new(SodiumResolver), Box::new(DefaultResolver))),
-        )
+    /// Override the maximum message length enforced by `write_message()` and `read_message()`,
+    /// which defaults to the spec's 65535 bytes.
+    ///
+    /// **This breaks interoperability with compliant implementations.** Only raise it in
+    /// controlled deployments where both peers agree on the same limit. The framed helpers
+    /// and `NoiseStream` keep the spec limit, as their length prefix is only 2 bytes.
+    ///
+    /// Building fails with `InitStage::ValidateMaxMessageLen` if `len` is larger than the
+    /// ciphers can seal under a single nonce (2^36 - 16 bytes, bounded by AES-GCM).
+    pub fn max_message_len(mut self, len: usize) -> Self {
+        self.max_len = len;
+        self
     }
 
-    /// Create a Builder with a custom crypto resolver.
-    pub fn with_resolver(params: NoiseParams, resolver: BoxedCryptoResolver) -> Self {
-        Builder { params, resolver, s: None, e_fixed: None, rs: None, plog: None, psks: [None; 10] }
+    /// Call `on_step` with the handshake hash after each handshake message is written or
+    /// read, e.g. to compare intermediate hashes against another implementation when
+    /// debugging interop. It only observes the handshake, and isn't carried over to transport
+    /// mode.
+    pub fn on_handshake_step<F>(mut self, on_step: F) -> Self
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        self.on_step = Some(Box::new(on_step));
+        self
+    }
+
+    /// Only build with params that `policy` allows, e.g. to pin down the primitives an
+    /// application negotiates.
+    ///
+    /// Building fails with `PatternProblem::PolicyViolation` for params it doesn't allow.
+    pub fn require(mut self, policy: Policy) -> Self {
+        self.policy = Some(policy);
+        self
     }
 
-    /// Specify a PSK (only used with `NoisePSK` base parameter)
+    /// Specify a PSK for the `pskN` modifier at `location`.
+    ///
+    /// Only locations the pattern declares are accepted. A PSK that is only known
+    /// mid-handshake can be left out here and given later with
+    /// [`HandshakeState::set_psk()`]; if it is still missing when its token is processed,
+    /// the handshake fails with [`StateProblem::MissingPsk`](crate::error::StateProblem::MissingPsk).
     pub fn psk(mut self, location: u8, key: &'builder [u8]) -> Self {
         self.psks[location as usize] = Some(key);
         self
     }
 
+    /// Mix PSKs into the messages at `placements`, for params whose protocol name declares no
+    /// `pskN` modifiers, e.g. `&[0, 2]` for `NN` acts like `NNpsk0+psk2`. The PSKs themselves
+    /// are still given by index with [`psk()`](Self::psk). Params that do declare `pskN`
+    /// modifiers keep their own positions and ignore `placements`.
+    ///
+    /// Building fails with `PatternProblem::InvalidPsk` if a placement doesn't fit the pattern.
+    /// See [`NoiseParams::place_psks()`](crate::params::NoiseParams::place_psks).
+    pub fn psk_placements(mut self, placements: &'builder [u8]) -> Self {
+        self.psk_places = Some(placements);
+        self
+    }
+
     /// Your static private key (can be generated with [`generate_keypair()`]).
     ///
+    /// Building fails with `InitStage::ValidateLocalStaticKey` if the pattern has no static
+    /// key for the role being built, e.g. either side of `NN` or the initiator of `NK`, as
+    /// the key would otherwise be silently ignored.
+    ///
     /// [`generate_keypair()`]: #method.generate_keypair
     pub fn local_private_key(mut self, key: &'builder [u8]) -> Self {
         self.s = Some(key);
+        self.s_pub = None;
         self
     }
 
+    /// Like [`local_private_key()`], but takes a 32-byte array (e.g. a Curve25519 key) so that
+    /// a key of the wrong length is caught at compile time. Use the slice version for other
+    /// lengths, like Curve448's 56 bytes.
+    ///
+    /// [`local_private_key()`]: #method.local_private_key
+    pub fn local_private_key_array(self, key: &'builder [u8; 32]) -> Self {
+        self.local_private_key(key)
+    }
+
+    /// Your static keypair, e.g. from [`generate_keypair()`]. Unlike with
+    /// [`local_private_key()`], the public key is taken as given instead of being derived
+    /// again when building.
+    ///
+    /// The public key must belong to the private key; debug builds of the default resolver
+    /// check that it does.
+    ///
+    /// [`generate_keypair()`]: #method.generate_keypair
+    /// [`local_private_key()`]: #method.local_private_key
+    pub fn local_keypair(mut self, keypair: &'builder Keypair) -> Self {
+        self.s = Some(&keypair.private);
+        self.s_pub = Some(&keypair.public);
+        self
+    }
+
+    // Reusing an ephemeral key across sessions breaks Noise's security, so keep this out of
+    // release builds unless tests explicitly opt in through the `test-utils` feature.
     #[doc(hidden)]
+    #[cfg(any(debug_assertions, feature = "test-utils"))]
     pub fn fixed_ephemeral_key_for_testing_only(mut self, key: &'builder [u8]) -> Self {
         self.e_fixed = Some(key);
         self
     }
 
+    /// Generate the ephemeral key when building instead of when writing the message that
+    /// sends it, to take the key generation off the latency of the first message. It's then
+    /// available from [`HandshakeState::get_local_ephemeral()`] right away.
+    ///
+    /// Roles that never send an ephemeral key, like the responder of a one-way pattern,
+    /// don't generate one either way.
+    pub fn eager_ephemeral(mut self, eager: bool) -> Self {
+        self.e_eager = eager;
+        self
+    }
+
+    /// Mix `context` into the chaining key before the handshake starts, to separate
+    /// deployments that would otherwise run the very same protocol. The chaining key becomes
+    /// the first output of `HKDF(ck, context)` right after it's initialized from the protocol
+    /// name; the handshake hash and message sizes are unaffected. Without it (the default),
+    /// the handshake is exactly the spec's.
+    ///
+    /// **This isn't part of the Noise spec.** Both peers must set the same context, or the
+    /// first encrypted message fails to decrypt. Note that an empty context still counts as
+    /// one.
+    pub fn hkdf_context(mut self, context: &'builder [u8]) -> Self {
+        self.hkdf_ctx = Some(context);
+        self
+    }
+
     /// Arbitrary data to be hashed in to the handshake hash value.
+    ///
+    /// Replaces any prologue given so far, including parts from [`prologue_part()`] and
+    /// [`prologue_reader()`].
+    ///
+    /// There's no size limit: the prologue is borrowed, not copied, and streamed into the hash
+    /// when building.
+    ///
+    /// [`prologue_part()`]: #method.prologue_part
+    /// [`prologue_reader()`]: #method.prologue_reader
     pub fn prologue(mut self, key: &'builder [u8]) -> Self {
-        self.plog = Some(key);
+        self.plog.clear();
+        self.plog.push(key);
+        #[cfg(feature = "std")]
+        {
+            self.plog_reader = None;
+        }
+        self
+    }
+
+    /// Appends `part` to the prologue (including one given to [`prologue()`]), for prologues
+    /// built from several pieces.
+    ///
+    /// The parts are hashed exactly as if they had been joined and passed to [`prologue()`].
+    ///
+    /// [`prologue()`]: #method.prologue
+    pub fn prologue_part(mut self, part: &'builder [u8]) -> Self {
+        self.plog.push(part);
+        self
+    }
+
+    /// Appends everything `reader` returns to the prologue, after the parts from
+    /// [`prologue()`] and [`prologue_part()`], for prologues too large to hold in memory or
+    /// produced on the fly.
+    ///
+    /// `reader` is read to the end when building, a chunk at a time, straight into the hash.
+    /// The result is the same as passing the bytes to [`prologue()`]. A second reader replaces
+    /// the first.
+    ///
+    /// Building fails with `InitStage::ReadPrologue` if `reader` returns an error.
+    ///
+    /// [`prologue()`]: #method.prologue
+    /// [`prologue_part()`]: #method.prologue_part
+    #[cfg(feature = "std")]
+    pub fn prologue_reader<R: io::Read + Send + 'builder>(mut self, reader: R) -> Self {
+        self.plog_reader = Some(Box::new(reader));
         self
     }
 
     /// The responder's static public key.
+    ///
+    /// Building fails with `Prerequisite::RemoteIsLocalStatic` if this is the public half of
+    /// [`local_private_key()`](#method.local_private_key).
     pub fn remote_public_key(mut self, pub_key: &'builder [u8]) -> Self {
         self.rs = Some(pub_key);
         self
     }
 
+    /// Like [`remote_public_key()`], but takes a 32-byte array (e.g. a Curve25519 key) so that
+    /// a key of the wrong length is caught at compile time. Use the slice version for other
+    /// lengths, like Curve448's 56 bytes.
+    ///
+    /// [`remote_public_key()`]: #method.remote_public_key
+    pub fn remote_public_key_array(self, pub_key: &'builder [u8; 32]) -> Self {
+        self.remote_public_key(pub_key)
+    }
+
     // TODO: performance issue w/ creating a new RNG and DH instance per call.
     /// Generate a new asymmetric keypair (for use as a static key).
     pub fn generate_keypair(&self) -> Result<Keypair, Error> {
@@ -143,6 +417,46 @@ impl<'builder> Builder<'builder> {
         Ok(Keypair { private, public })
     }
 
+    /// Generate a new random pre-shared key of the right length for [`psk()`], from the
+    /// resolver's RNG.
+    ///
+    /// [`psk()`]: #method.psk
+    pub fn generate_psk(&self) -> Result<Vec<u8>, Error> {
+        let mut rng = self.resolver.resolve_rng().ok_or(InitStage::GetRngImpl)?;
+        let mut psk = vec![0u8; PSKLEN];
+        rng.fill_bytes(&mut psk);
+        Ok(psk)
+    }
+
+    /// Check that the resolver provides every primitive `params` needs, without building a
+    /// handshake, e.g. to quickly reject a protocol offered during negotiation.
+    ///
+    /// Only the resolver is used (along with the RNG, if one was given with [`rng()`]), so a
+    /// builder set up for one protocol can vet others.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Init` naming the first primitive that is missing, in the order
+    /// `GetRngImpl`, `GetCipherImpl`, `GetHashImpl`, `GetDhImpl`, then `GetKemImpl`.
+    ///
+    /// [`rng()`]: #method.rng
+    pub fn can_resolve(&self, params: &NoiseParams) -> Result<(), Error> {
+        if self.rng.is_none() {
+            self.resolver.resolve_rng().ok_or(InitStage::GetRngImpl)?;
+        }
+        self.resolver.resolve_cipher(&params.cipher).ok_or(InitStage::GetCipherImpl)?;
+        self.resolver.resolve_hash(&params.hash).ok_or(InitStage::GetHashImpl)?;
+        for role in [DhRole::Static, DhRole::Ephemeral] {
+            self.resolver.resolve_dh_for(role, &params.dh).ok_or(InitStage::GetDhImpl)?;
+        }
+        #[cfg(feature = "hfs")]
+        if params.handshake.is_hfs() {
+            let kem = params.kem.ok_or(InitStage::GetKemImpl)?;
+            self.resolver.resolve_kem(&kem).ok_or(InitStage::GetKemImpl)?;
+        }
+        Ok(())
+    }
+
     /// Build a [`HandshakeState`] for the side who will initiate the handshake (send the first message)
     pub fn build_initiator(self) -> Result<HandshakeState, Error> {
         self.build(true)
@@ -153,38 +467,176 @@ impl<'builder> Builder<'builder> {
         self.build(false)
     }
 
-    fn build(self, initiator: bool) -> Result<HandshakeState, Error> {
-        if self.s.is_none() && self.params.handshake.pattern.needs_local_static_key(initiator) {
-            bail!(Prerequisite::LocalPrivateKey);
+    /// Build an initiator and run the whole handshake over a blocking `stream`, such as a
+    /// `TcpStream`, with empty payloads.
+    ///
+    /// Each handshake message goes on the wire prefixed with its length as a 2-byte
+    /// big-endian integer, and the peer is expected to call [`accept()`](#method.accept).
+    ///
+    /// # Errors
+    ///
+    /// Will result in an `io::Error` if the stream fails or closes early, and in one of kind
+    /// `InvalidData` if building the session or the handshake itself fails.
+    #[cfg(feature = "std")]
+    pub fn connect<S: io::Read + io::Write>(self, stream: &mut S) -> io::Result<TransportState> {
+        complete_handshake(self.build_initiator().map_err(invalid_data)?, stream)
+    }
+
+    /// Like [`connect()`](#method.connect), but builds a responder.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`connect()`](#method.connect).
+    #[cfg(feature = "std")]
+    pub fn accept<S: io::Read + io::Write>(self, stream: &mut S) -> io::Result<TransportState> {
+        complete_handshake(self.build_responder().map_err(invalid_data)?, stream)
+    }
+
+    /// Restore a [`TransportState`] saved with [`TransportState::save_transport_state()`],
+    /// resolving its ciphers with this builder. The params must match those of the saved
+    /// session; keys, PSKs and the prologue given to the builder are ignored.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Input` if `state` is malformed or doesn't fit the params' hash
+    /// and DH lengths, and in `Error::Init` if the resolver lacks a primitive.
+    pub fn restore_transport_state(self, state: &[u8]) -> Result<TransportState, Error> {
+        if let Some(policy) = &self.policy {
+            policy.check(&self.params)?;
         }
 
-        if self.rs.is_none() && self.params.handshake.pattern.need_known_remote_pubkey(initiator) {
-            bail!(Prerequisite::RemotePublicKey);
+        let hash = self.resolver.resolve_hash(&self.params.hash).ok_or(InitStage::GetHashImpl)?;
+        let dh = self.resolver.resolve_dh(&self.params.dh).ok_or(InitStage::GetDhImpl)?;
+        let cipher1 =
+            self.resolver.resolve_cipher(&self.params.cipher).ok_or(InitStage::GetCipherImpl)?;
+        let cipher2 =
+            self.resolver.resolve_cipher(&self.params.cipher).ok_or(InitStage::GetCipherImpl)?;
+        let cipherstates = CipherStates::new(CipherState::new(cipher1), CipherState::new(cipher2))?;
+
+        let max_len = Self::validate_max_len(self.max_len)?;
+        TransportState::restore(state, cipherstates, hash, self.params, dh.pub_len(), max_len)
+    }
+
+    pub(crate) fn get_params(&self) -> &NoiseParams {
+        &self.params
+    }
+
+    /// Swap the params, keeping the keys, prologue and everything else.
+    pub(crate) fn with_params(mut self, params: NoiseParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    pub(crate) fn has_remote_public_key(&self) -> bool {
+        self.rs.is_some()
+    }
+
+    /// The whole prologue, for a caller that needs to keep a copy. A prologue reader is read
+    /// to the end, and replaced by what it returned.
+    pub(crate) fn take_prologue(&mut self) -> Result<Vec<u8>, Error> {
+        #[allow(unused_mut)]
+        let mut prologue = self.plog.concat();
+        #[cfg(feature = "std")]
+        if let Some(mut reader) = self.plog_reader.take() {
+            let start = prologue.len();
+            reader.read_to_end(&mut prologue).map_err(|_| InitStage::ReadPrologue)?;
+            self.plog_reader = Some(Box::new(io::Cursor::new(prologue[start..].to_vec())));
         }
+        Ok(prologue)
+    }
 
-        let rng = self.resolver.resolve_rng().ok_or(InitStage::GetRngImpl)?;
+    fn build(mut self, initiator: bool) -> Result<HandshakeState, Error> {
+        if let Some(placements) = self.psk_places {
+            self.params.place_psks(placements)?;
+        }
+
+        if let Some(policy) = &self.policy {
+            policy.check(&self.params)?;
+        }
+
+        let pattern = self.params.handshake.pattern;
+        let needs_s = pattern.needs_local_static_key(initiator);
+        if self.s.is_none() && needs_s {
+            bail!(Prerequisite::LocalPrivateKey { pattern, initiator });
+        }
+
+        if self.rs.is_none() && pattern.need_known_remote_pubkey(initiator) {
+            bail!(Prerequisite::RemotePublicKey { pattern, initiator });
+        }
+
+        if self.s.is_some() && !needs_s {
+            bail!(InitStage::ValidateLocalStaticKey);
+        }
+
+        let max_len = Self::validate_max_len(self.max_len)?;
+
+        for (location, psk) in self.psks.iter().enumerate() {
+            let location = location as u8;
+            let needed =
+                self.params.handshake.modifiers.list.contains(&HandshakeModifier::Psk(location));
+            if psk.is_some() && !needed {
+                bail!(InitStage::ValidatePskPosition);
+            }
+        }
+
+        let mut rng = match self.rng {
+            Some(rng) => rng,
+            None => self.resolver.resolve_rng().ok_or(InitStage::GetRngImpl)?,
+        };
         let cipher =
             self.resolver.resolve_cipher(&self.params.cipher).ok_or(InitStage::GetCipherImpl)?;
         let hash = self.resolver.resolve_hash(&self.params.hash).ok_or(InitStage::GetHashImpl)?;
-        let mut s_dh = self.resolver.resolve_dh(&self.params.dh).ok_or(InitStage::GetDhImpl)?;
-        let mut e_dh = self.resolver.resolve_dh(&self.params.dh).ok_or(InitStage::GetDhImpl)?;
+        let mut s_dh = self
+            .resolver
+            .resolve_dh_for(DhRole::Static, &self.params.dh)
+            .ok_or(InitStage::GetDhImpl)?;
+        let mut e_dh = self
+            .resolver
+            .resolve_dh_for(DhRole::Ephemeral, &self.params.dh)
+            .ok_or(InitStage::GetDhImpl)?;
         let cipher1 =
             self.resolver.resolve_cipher(&self.params.cipher).ok_or(InitStage::GetCipherImpl)?;
         let cipher2 =
             self.resolver.resolve_cipher(&self.params.cipher).ok_or(InitStage::GetCipherImpl)?;
+        let transport_hash =
+            self.resolver.resolve_hash(&self.params.hash).ok_or(InitStage::GetHashImpl)?;
         let handshake_cipherstate = CipherState::new(cipher);
         let cipherstates = CipherStates::new(CipherState::new(cipher1), CipherState::new(cipher2))?;
 
-        let s = match self.s {
-            Some(k) => {
-                (&mut *s_dh).set(k);
+        if self.s.is_some_and(|k| !s_dh.accepts_privkey(k))
+            || self.e_fixed.is_some_and(|k| !e_dh.accepts_privkey(k))
+        {
+            bail!(InitStage::ValidatePrivateKey);
+        }
+
+        let s = match (self.s, self.s_pub) {
+            (Some(k), Some(pubkey)) => {
+                if pubkey.len() != s_dh.pub_len() {
+                    bail!(InitStage::ValidateKeyLengths);
+                }
+                (*s_dh).set_keypair(k, pubkey);
+                Toggle::on(s_dh)
+            },
+            (Some(k), None) => {
+                (*s_dh).set(k);
                 Toggle::on(s_dh)
             },
-            None => Toggle::off(s_dh),
+            (None, _) => Toggle::off(s_dh),
         };
 
+        if let (Some(s), Some(rs)) = (s.get(), self.rs) {
+            if bool::from(s.pubkey().ct_eq(rs)) {
+                bail!(Prerequisite::RemoteIsLocalStatic);
+            }
+        }
+
+        let sends_e = initiator || !(pattern.is_oneway() || self.params.handshake.is_fallback());
+        let e_fixed = self.e_fixed.is_some();
+        let e_eager = !e_fixed && self.e_eager && sends_e;
         if let Some(fixed_k) = self.e_fixed {
-            (&mut *e_dh).set(fixed_k);
+            (*e_dh).set(fixed_k);
+        } else if e_eager {
+            e_dh.generate(&mut *rng);
         }
         let e = Toggle::off(e_dh);
 
@@ -211,25 +663,51 @@ impl<'builder> Builder<'builder> {
             }
         }
 
+        let plog = &self.plog;
+        #[cfg(feature = "std")]
+        let mut plog_reader = self.plog_reader;
+        let prologue = |hasher: &mut dyn Hash| {
+            for part in plog {
+                hasher.input(part);
+            }
+            #[cfg(feature = "std")]
+            if let Some(reader) = &mut plog_reader {
+                input_reader(hasher, reader)?;
+            }
+            Ok(())
+        };
+
         let mut hs = HandshakeState::new(
             rng,
             handshake_cipherstate,
             hash,
             s,
             e,
-            self.e_fixed.is_some(),
+            e_fixed,
+            e_eager,
             rs,
             re,
             initiator,
             self.params,
             psks,
-            self.plog.unwrap_or_else(|| &[0u8; 0]),
+            self.hkdf_ctx,
+            prologue,
             cipherstates,
+            transport_hash,
         )?;
+        hs.max_len = max_len;
+        hs.on_step = self.on_step;
         Self::resolve_kem(self.resolver, &mut hs)?;
         Ok(hs)
     }
 
+    fn validate_max_len(len: usize) -> Result<usize, Error> {
+        if len as u64 > MAXAEADMSGLEN {
+            bail!(InitStage::ValidateMaxMessageLen);
+        }
+        Ok(len)
+    }
+
     #[cfg(not(feature = "hfs"))]
     fn resolve_kem(_: Box<dyn CryptoResolver>, _: &mut HandshakeState) -> Result<(), Error> {
         // HFS is disabled, return nothing
@@ -253,14 +731,61 @@ impl<'builder> Builder<'builder> {
     }
 }
 
+/// Drives `handshake` to completion over `stream`, in whichever direction each message goes.
+#[cfg(feature = "std")]
+fn complete_handshake<S: io::Read + io::Write>(
+    mut handshake: HandshakeState,
+    stream: &mut S,
+) -> io::Result<TransportState> {
+    use crate::constants::{LENLEN, MAXMSGLEN};
+
+    let mut frame = Vec::with_capacity(LENLEN + MAXMSGLEN);
+    while !handshake.is_handshake_finished() {
+        frame.clear();
+        if handshake.is_my_turn() {
+            handshake.write_message_framed(&[], &mut frame).map_err(invalid_data)?;
+            stream.write_all(&frame)?;
+            stream.flush()?;
+        } else {
+            // Only the length prefix is read here, to know how much of the stream is the frame.
+            frame.resize(LENLEN, 0);
+            stream.read_exact(&mut frame)?;
+            let len = u16::from_be_bytes([frame[0], frame[1]]) as usize;
+            frame.resize(LENLEN + len, 0);
+            stream.read_exact(&mut frame[LENLEN..])?;
+            handshake.read_message_framed(&frame).map_err(invalid_data)?;
+        }
+    }
+    handshake.into_transport_mode().map_err(invalid_data)
+}
+
+#[cfg(feature = "std")]
+fn invalid_data(e: Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Feeds everything `reader` returns to `hasher`, a chunk at a time.
+#[cfg(feature = "std")]
+fn input_reader(hasher: &mut dyn Hash, reader: &mut dyn io::Read) -> Result<(), Error> {
+    let mut chunk = [0u8; 4096];
+    loop {
+        match reader.read(&mut chunk) {
+            Ok(0) => return Ok(()),
+            Ok(len) => hasher.input(&chunk[..len]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(_) => bail!(InitStage::ReadPrologue),
+        }
+    }
+}
+
 #[cfg(test)]
-#[cfg(any(feature = "default-resolver", feature = "ring-accelerated"))]
+#[cfg(all(feature = "default-resolver", feature = "std"))]
 mod tests {
     use super::*;
 
     #[test]
     fn test_builder() {
-        let _noise = Builder::new("Noise_NN_25519_ChaChaPoly_SHA256".parse().unwrap())
+        let _noise = Builder::new("Noise_XX_25519_ChaChaPoly_SHA256".parse().unwrap())
             .prologue(&[2, 2, 2, 2, 2, 2, 2, 2])
             .local_private_key(&[0u8; 32])
             .build_initiator()
@@ -277,7 +802,7 @@ mod tests {
 
     #[test]
     fn test_builder_bad_spec() {
-        let params: ::std::result::Result<NoiseParams, _> =
+        let params: ::core::result::Result<NoiseParams, _> =
             "Noise_NK_25519_ChaChaPoly_BLAH256".parse();
 
         if params.is_ok() {
@@ -304,17 +829,17 @@ mod tests {
         let mut keypair_2 = Keypair { private: vec![0x01; 32], public: vec![0x01; 32] };
 
         // If both private and public are the same, return true
-        assert_eq!(keypair_1 == keypair_2, true);
+        assert!(keypair_1 == keypair_2);
 
         // If either public or private are different, return false
 
         // Wrong private
         keypair_2.private = vec![0x50; 32];
-        assert_eq!(keypair_1 == keypair_2, false);
+        assert!(!(keypair_1 == keypair_2));
         // Reset to original
         keypair_2.private = vec![0x01; 32];
         // Wrong public
         keypair_2.public = vec![0x50; 32];
-        assert_eq!(keypair_1 == keypair_2, false);
+        assert!(!(keypair_1 == keypair_2));
     }
 }
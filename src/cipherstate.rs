@@ -1,28 +1,64 @@
+use alloc::boxed::Box;
 use crate::{
-    constants::TAGLEN,
+    constants::{CIPHERKEYLEN, TAGLEN},
     error::{Error, InitStage, StateProblem},
     types::Cipher,
+    utils::redacted,
 };
+use core::fmt;
+use subtle::ConstantTimeEq;
+use zeroize::{Zeroize, Zeroizing};
 
 pub(crate) struct CipherState {
-    cipher:  Box<dyn Cipher>,
-    n:       u64,
-    has_key: bool,
+    cipher:        Box<dyn Cipher>,
+    key:           Zeroizing<[u8; CIPHERKEYLEN]>,
+    n:             u64,
+    has_key:       bool,
+    key_known:     bool,
+    keep_previous: bool,
+    previous:      Option<Zeroizing<[u8; CIPHERKEYLEN]>>,
 }
 
 impl CipherState {
     pub fn new(cipher: Box<dyn Cipher>) -> Self {
-        Self { cipher, n: 0, has_key: false }
+        Self {
+            cipher,
+            key: Zeroizing::new([0u8; CIPHERKEYLEN]),
+            n: 0,
+            has_key: false,
+            key_known: true,
+            keep_previous: false,
+            previous: None,
+        }
     }
 
     pub fn name(&self) -> &'static str {
         self.cipher.name()
     }
 
-    pub fn set(&mut self, key: &[u8], n: u64) {
+    /// Fails closed on an all-zero key rather than encrypting with it.
+    pub fn set(&mut self, key: &[u8], n: u64) -> Result<(), Error> {
+        if bool::from(key.ct_eq(&[0u8; CIPHERKEYLEN])) {
+            bail!(StateProblem::ZeroKey);
+        }
+        self.restore(key, n);
+        Ok(())
+    }
+
+    /// Like `set()`, for a key it has already accepted.
+    pub fn restore(&mut self, key: &[u8], n: u64) {
         self.cipher.set(key);
+        copy_slices!(key, self.key);
         self.n = n;
         self.has_key = true;
+        self.key_known = true;
+        self.previous = None;
+    }
+
+    /// Keep the key replaced by the next rekey, and fall back to it when decryption with the
+    /// new one fails, until a message decrypts with the new key.
+    pub fn keep_previous_key(&mut self) {
+        self.keep_previous = true;
     }
 
     pub fn encrypt_ad(
@@ -34,53 +70,167 @@ impl CipherState {
         if !self.has_key {
             bail!(StateProblem::MissingKeyMaterial);
         }
+        check_nonce(self.n)?;
 
         let len = self.cipher.encrypt(self.n, authtext, plaintext, out);
         self.n = self.n.checked_add(1).unwrap();
         Ok(len)
     }
 
-    pub fn decrypt_ad(
+    pub fn encrypt_in_place_ad(
         &mut self,
         authtext: &[u8],
-        ciphertext: &[u8],
-        out: &mut [u8],
-    ) -> Result<usize, ()> {
-        if (ciphertext.len() < TAGLEN) || (out.len() < (ciphertext.len() - TAGLEN) || !self.has_key)
-        {
-            return Err(());
+        in_out: &mut [u8],
+        plaintext_len: usize,
+    ) -> Result<usize, Error> {
+        if !self.has_key {
+            bail!(StateProblem::MissingKeyMaterial);
         }
+        check_nonce(self.n)?;
 
-        let len = self.cipher.decrypt(self.n, authtext, ciphertext, out);
+        let len = self.cipher.encrypt_in_place(self.n, authtext, in_out, plaintext_len);
         self.n = self.n.checked_add(1).unwrap();
-        len
-    }
-
-    pub fn encrypt(&mut self, plaintext: &[u8], out: &mut [u8]) -> Result<usize, Error> {
-        self.encrypt_ad(&[0u8; 0], plaintext, out)
+        Ok(len)
     }
 
-    pub fn decrypt(&mut self, ciphertext: &[u8], out: &mut [u8]) -> Result<usize, ()> {
-        self.decrypt_ad(&[0u8; 0], ciphertext, out)
+    pub fn decrypt_ad(
+        &mut self,
+        authtext: &[u8],
+        ciphertext: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, Error> {
+        check_decrypt(self.has_key, ciphertext, out)?;
+        check_nonce(self.n)?;
+
+        let mut len = self.cipher.decrypt(self.n, authtext, ciphertext, out);
+        if len.is_ok() {
+            // The peer has switched to the new key, so nothing under the old one may follow.
+            self.previous = None;
+        } else if let Some(previous) = &self.previous {
+            self.cipher.set(&previous[..]);
+            len = self.cipher.decrypt(self.n, authtext, ciphertext, out);
+            if self.key_known {
+                self.cipher.set(&self.key[..]);
+            } else {
+                // The previous key is the one the unknown key was rekeyed from.
+                self.cipher.rekey();
+            }
+        }
+        self.n = self.n.checked_add(1).unwrap();
+        len.map_err(|_| decrypt_failed(&mut out[..ciphertext.len() - TAGLEN]))
     }
 
+    /// Rekeys with `Cipher::rekey()`, and keeps tracking the key if it is the spec's
+    /// `REKEY(k)`. A cipher overriding `rekey()` may derive another one, which `key()` then
+    /// can't return.
     pub fn rekey(&mut self) {
+        self.save_previous();
+        if !self.key_known {
+            self.cipher.rekey();
+            return;
+        }
+
+        let expected = rekeyed(&*self.cipher);
         self.cipher.rekey();
+        // Compare the keys by what they rekey to, as the cipher can't give its key back.
+        let derived = rekeyed(&*self.cipher);
+        self.cipher.set(&expected[..]);
+        if bool::from(rekeyed(&*self.cipher).ct_eq(&derived[..])) {
+            self.key = expected;
+        } else {
+            self.cipher.set(&self.key[..]);
+            self.cipher.rekey();
+            self.key.zeroize();
+            self.key_known = false;
+        }
     }
 
     pub fn rekey_manually(&mut self, key: &[u8]) {
+        self.save_previous();
         self.cipher.set(key);
+        copy_slices!(key, self.key);
+        self.key_known = true;
+    }
+
+    fn save_previous(&mut self) {
+        self.previous = match self.key() {
+            Ok(key) if self.keep_previous => Some(Zeroizing::new(*key)),
+            _ => None,
+        };
+    }
+
+    /// Fails with `StateProblem::UnknownKey` after a custom `Cipher::rekey()`.
+    pub fn key(&self) -> Result<&[u8; CIPHERKEYLEN], Error> {
+        if !self.has_key {
+            bail!(StateProblem::MissingKeyMaterial);
+        } else if !self.key_known {
+            bail!(StateProblem::UnknownKey);
+        }
+        Ok(&self.key)
     }
 
     pub fn nonce(&self) -> u64 {
         self.n
     }
 
-    pub fn set_nonce(&mut self, nonce: u64) {
+    pub fn set_nonce(&mut self, nonce: u64) -> Result<(), Error> {
+        check_nonce(nonce)?;
         self.n = nonce;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for CipherState {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("CipherState")
+            .field("cipher", &self.name())
+            .field("key", &redacted(self.has_key))
+            .field("nonce", &self.n)
+            .finish()
+    }
+}
+
+/// 2^64-1 is reserved by the spec, see "5.1. The CipherState object", so a session that gets
+/// there is used up.
+fn check_nonce(nonce: u64) -> Result<(), Error> {
+    if nonce == u64::MAX {
+        bail!(StateProblem::Exhausted);
+    }
+    Ok(())
+}
+
+/// Checks everything but the tag before decrypting, so that `Error::Decrypt` only ever means
+/// that the message was not authentic.
+fn check_decrypt(has_key: bool, ciphertext: &[u8], out: &[u8]) -> Result<(), Error> {
+    if !has_key {
+        bail!(StateProblem::MissingKeyMaterial);
+    } else if ciphertext.len() < TAGLEN {
+        // Too short to carry a tag, so it can't be authentic.
+        bail!(Error::Decrypt);
+    } else if out.len() < ciphertext.len() - TAGLEN {
+        bail!(Error::Input);
     }
+    Ok(())
+}
+
+/// The spec's `REKEY(k)`, see "4.2. Cipher functions", i.e. what the default
+/// `Cipher::rekey()` turns the cipher's key into.
+fn rekeyed(cipher: &dyn Cipher) -> Zeroizing<[u8; CIPHERKEYLEN]> {
+    let mut ciphertext = Zeroizing::new([0u8; CIPHERKEYLEN + TAGLEN]);
+    cipher.encrypt(u64::MAX, &[], &[0; CIPHERKEYLEN], &mut ciphertext[..]);
+    let mut key = Zeroizing::new([0u8; CIPHERKEYLEN]);
+    key.copy_from_slice(&ciphertext[..CIPHERKEYLEN]);
+    key
 }
 
+/// Wipes what a failed decryption may have left in `out`, be it ciphertext or unauthenticated
+/// plaintext, so that a caller ignoring the error can't mistake it for a message.
+fn decrypt_failed(out: &mut [u8]) -> Error {
+    out.zeroize();
+    Error::Decrypt
+}
+
+#[derive(Debug)]
 pub(crate) struct CipherStates(pub CipherState, pub CipherState);
 
 impl CipherStates {
@@ -126,6 +276,7 @@ impl StatelessCipherState {
         if !self.has_key {
             bail!(StateProblem::MissingKeyMaterial);
         }
+        check_nonce(nonce)?;
         Ok(self.cipher.encrypt(nonce, authtext, plaintext, out))
     }
 
@@ -135,21 +286,13 @@ impl StatelessCipherState {
         authtext: &[u8],
         ciphertext: &[u8],
         out: &mut [u8],
-    ) -> Result<usize, ()> {
-        if (ciphertext.len() < TAGLEN) || (out.len() < (ciphertext.len() - TAGLEN) || !self.has_key)
-        {
-            return Err(());
-        }
-
-        self.cipher.decrypt(nonce, authtext, ciphertext, out)
-    }
-
-    pub fn encrypt(&self, nonce: u64, plaintext: &[u8], out: &mut [u8]) -> Result<usize, Error> {
-        self.encrypt_ad(nonce, &[], plaintext, out)
-    }
+    ) -> Result<usize, Error> {
+        check_decrypt(self.has_key, ciphertext, out)?;
+        check_nonce(nonce)?;
 
-    pub fn decrypt(&self, nonce: u64, ciphertext: &[u8], out: &mut [u8]) -> Result<usize, ()> {
-        self.decrypt_ad(nonce, &[], ciphertext, out)
+        self.cipher
+            .decrypt(nonce, authtext, ciphertext, out)
+            .map_err(|_| decrypt_failed(&mut out[..ciphertext.len() - TAGLEN]))
     }
 
     pub fn rekey(&mut self) {
@@ -161,12 +304,22 @@ impl StatelessCipherState {
     }
 }
 
+impl fmt::Debug for StatelessCipherState {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("StatelessCipherState")
+            .field("cipher", &self.cipher.name())
+            .field("key", &redacted(self.has_key))
+            .finish()
+    }
+}
+
 impl From<CipherState> for StatelessCipherState {
     fn from(other: CipherState) -> Self {
         Self { cipher: other.cipher, has_key: other.has_key }
     }
 }
 
+#[derive(Debug)]
 pub(crate) struct StatelessCipherStates(pub StatelessCipherState, pub StatelessCipherState);
 
 impl From<CipherStates> for StatelessCipherStates {
@@ -1,11 +1,15 @@
 pub const PSKLEN: usize = 32;
 pub const CIPHERKEYLEN: usize = 32;
 pub const TAGLEN: usize = 16;
+pub const LENLEN: usize = 2;
 
 pub const MAXHASHLEN: usize = 64;
-pub const MAXBLOCKLEN: usize = 128;
+pub const MAXBLOCKLEN: usize = 136;
 pub const MAXDHLEN: usize = 56;
 pub const MAXMSGLEN: usize = 65535;
+/// The largest message AES-GCM can seal under one nonce: 2^36 - 32 bytes of plaintext plus
+/// the tag. ChaChaPoly allows more, so this bounds every cipher.
+pub const MAXAEADMSGLEN: u64 = (1 << 36) - 32 + TAGLEN as u64;
 
 #[cfg(feature = "hfs")]
 pub const MAXKEMPUBLEN: usize = 4096;
@@ -1,6 +1,7 @@
 //! All error types used by Snow operations.
 
-use std::fmt;
+use crate::params::HandshakePattern;
+use core::fmt;
 
 /// All errors in snow will include an `ErrorKind`.
 #[allow(missing_docs)]
@@ -48,6 +49,20 @@ pub enum PatternProblem {
     UnsupportedModifier,
     #[cfg(feature = "hfs")]
     UnsupportedKemType,
+    /// The params are supported, but a [`Policy`](crate::params::Policy) doesn't allow this
+    /// component of them.
+    PolicyViolation(PolicyComponent),
+}
+
+/// The component of the params that a `Policy` rejected.
+#[allow(missing_docs)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PolicyComponent {
+    Pattern,
+    Modifier,
+    Dh,
+    Cipher,
+    Hash,
 }
 
 impl From<PatternProblem> for Error {
@@ -70,7 +85,18 @@ pub enum InitStage {
     GetHashImpl,
     #[cfg(feature = "hfs")]
     GetKemImpl,
+    /// A PSK was given for a location the pattern has no `pskN` modifier for, e.g. any PSK
+    /// for a non-PSK pattern like `NN`, so it would never be mixed in.
     ValidatePskPosition,
+    ValidateMaxMessageLen,
+    /// A local private key was given, but the pattern has no static key for this role.
+    ValidateLocalStaticKey,
+    /// A private key was given that the DH implementation can't use, see
+    /// [`Dh::accepts_privkey()`](crate::types::Dh::accepts_privkey).
+    ValidatePrivateKey,
+    /// The reader given to `Builder::prologue_reader()` (with the `std` feature) returned an
+    /// error.
+    ReadPrologue,
 }
 
 impl From<InitStage> for Error {
@@ -83,8 +109,14 @@ impl From<InitStage> for Error {
 #[allow(missing_docs)]
 #[derive(Debug)]
 pub enum Prerequisite {
-    LocalPrivateKey,
-    RemotePublicKey,
+    /// The pattern needs a local static key in this role, but none was provided.
+    LocalPrivateKey { pattern: HandshakePattern, initiator: bool },
+    /// The pattern needs the remote's static key in advance in this role, but none was
+    /// provided.
+    RemotePublicKey { pattern: HandshakePattern, initiator: bool },
+    /// The remote public key is our own static public key, i.e. the handshake would be
+    /// with ourselves. This is usually a copy-paste mistake in the configuration.
+    RemoteIsLocalStatic,
 }
 
 impl From<Prerequisite> for Error {
@@ -93,6 +125,29 @@ impl From<Prerequisite> for Error {
     }
 }
 
+impl fmt::Display for Prerequisite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let role = |initiator: bool| if initiator { "initiator" } else { "responder" };
+        match self {
+            Prerequisite::LocalPrivateKey { pattern, initiator } => write!(
+                f,
+                "pattern {} as {} requires a local private key",
+                pattern.as_str(),
+                role(*initiator)
+            ),
+            Prerequisite::RemotePublicKey { pattern, initiator } => write!(
+                f,
+                "pattern {} as {} requires a remote public key",
+                pattern.as_str(),
+                role(*initiator)
+            ),
+            Prerequisite::RemoteIsLocalStatic => {
+                write!(f, "remote public key is the local static public key")
+            },
+        }
+    }
+}
+
 /// Specific errors in the state machine.
 #[allow(missing_docs)]
 #[derive(Debug)]
@@ -105,6 +160,17 @@ pub enum StateProblem {
     HandshakeAlreadyFinished,
     OneWay,
     StatelessTransportMode,
+    Exhausted,
+    /// The remote static key can't be set anymore, or at all: it's received in a handshake
+    /// message, or the handshake has already started.
+    RemoteStaticFixed,
+    /// A cipher key came out all zeros, which only a broken DH or hash implementation (or a
+    /// corrupted saved state) produces. The session refuses to use it.
+    ZeroKey,
+    /// A cipher overriding [`Cipher::rekey()`](crate::types::Cipher::rekey) rekeyed to a key
+    /// other than the spec's, so the session doesn't know it and can't save it or mix a PSK
+    /// into it.
+    UnknownKey,
 }
 
 impl From<StateProblem> for Error {
@@ -113,20 +179,121 @@ impl From<StateProblem> for Error {
     }
 }
 
+impl fmt::Display for PatternProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PatternProblem::TooFewParameters => "protocol name has too few parameters",
+            PatternProblem::UnsupportedHandshakeType => "unsupported handshake pattern",
+            PatternProblem::UnsupportedBaseType => "unsupported protocol base",
+            PatternProblem::UnsupportedHashType => "unsupported hash function",
+            PatternProblem::UnsupportedDhType => "unsupported DH function",
+            PatternProblem::UnsupportedCipherType => "unsupported cipher",
+            PatternProblem::InvalidPsk => "invalid or repeated psk modifier",
+            PatternProblem::UnsupportedModifier => "unsupported or repeated handshake modifier",
+            #[cfg(feature = "hfs")]
+            PatternProblem::UnsupportedKemType => "unsupported KEM",
+            PatternProblem::PolicyViolation(component) => {
+                return write!(f, "{} not allowed by the policy", component);
+            },
+        })
+    }
+}
+
+impl fmt::Display for PolicyComponent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PolicyComponent::Pattern => "handshake pattern",
+            PolicyComponent::Modifier => "handshake modifier",
+            PolicyComponent::Dh => "DH function",
+            PolicyComponent::Cipher => "cipher",
+            PolicyComponent::Hash => "hash function",
+        })
+    }
+}
+
+impl fmt::Display for InitStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InitStage::ValidateKeyLengths => "key lengths don't match the DH function",
+            InitStage::ValidatePskLengths => "PSK is not 32 bytes",
+            InitStage::ValidateCipherTypes => "cipher states use different ciphers",
+            InitStage::GetRngImpl => "resolver has no RNG",
+            InitStage::GetDhImpl => "resolver has no implementation of the DH function",
+            InitStage::GetCipherImpl => "resolver has no implementation of the cipher",
+            InitStage::GetHashImpl => "resolver has no implementation of the hash function",
+            #[cfg(feature = "hfs")]
+            InitStage::GetKemImpl => "resolver has no implementation of the KEM",
+            InitStage::ValidatePskPosition => "PSK given for a location the pattern doesn't use",
+            InitStage::ValidateMaxMessageLen => "maximum message length exceeds the AEAD limit",
+            InitStage::ValidateLocalStaticKey => {
+                "local private key given, but the pattern has no local static key"
+            },
+            InitStage::ValidatePrivateKey => "private key not usable with the DH implementation",
+            InitStage::ReadPrologue => "failed to read the prologue",
+        })
+    }
+}
+
+impl fmt::Display for StateProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StateProblem::MissingKeyMaterial => "no key established yet",
+            StateProblem::MissingPsk => "PSK for the next psk token is missing",
+            StateProblem::NotTurnToWrite => "not our turn to write",
+            StateProblem::NotTurnToRead => "not our turn to read",
+            StateProblem::HandshakeNotFinished => "handshake not finished",
+            StateProblem::HandshakeAlreadyFinished => "handshake already finished",
+            StateProblem::OneWay => "one-way pattern only sends from initiator to responder",
+            StateProblem::StatelessTransportMode => "not available in stateless transport mode",
+            StateProblem::Exhausted => "nonces exhausted",
+            StateProblem::RemoteStaticFixed => "remote static key can no longer be set",
+            StateProblem::ZeroKey => "cipher key is all zeros",
+            StateProblem::UnknownKey => "cipher key unknown after a custom rekey",
+        })
+    }
+}
+
+/// Only says what kind of error it is. The specific problem is its payload, and also its
+/// `source()` with the `std` feature.
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Error::Pattern(reason) => write!(f, "pattern error: {:?}", reason),
-            Error::Init(reason) => write!(f, "initialization error: {:?}", reason),
-            Error::Prereq(reason) => write!(f, "prerequisite error: {:?}", reason),
-            Error::State(reason) => write!(f, "state error: {:?}", reason),
+            Error::Pattern(_) => write!(f, "pattern error"),
+            Error::Init(_) => write!(f, "initialization error"),
+            Error::Prereq(_) => write!(f, "prerequisite error"),
+            Error::State(_) => write!(f, "state error"),
             Error::Input => write!(f, "input error"),
             Error::Dh => write!(f, "diffie-hellman error"),
-            Error::Decrypt => write!(f, "decrypt error"),
+            Error::Decrypt => write!(f, "decrypt error: message failed authentication"),
             #[cfg(feature = "hfs")]
             Error::Kem => write!(f, "kem error"),
         }
     }
 }
 
-impl std::error::Error for Error {}
+#[cfg(feature = "std")]
+impl std::error::Error for PatternProblem {}
+
+#[cfg(feature = "std")]
+impl std::error::Error for InitStage {}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Prerequisite {}
+
+#[cfg(feature = "std")]
+impl std::error::Error for StateProblem {}
+
+/// The specific problem behind `Pattern`, `Init`, `Prereq` and `State` errors is their
+/// source. The primitives don't report a cause for the other kinds.
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Pattern(reason) => Some(reason),
+            Error::Init(reason) => Some(reason),
+            Error::Prereq(reason) => Some(reason),
+            Error::State(reason) => Some(reason),
+            _ => None,
+        }
+    }
+}
@@ -4,21 +4,24 @@ use crate::constants::{CIPHERKEYLEN, MAXHASHLEN};
 use crate::constants::{MAXKEMCTLEN, MAXKEMPUBLEN, MAXKEMSSLEN};
 #[cfg(feature = "hfs")]
 use crate::types::Kem;
+use alloc::{boxed::Box, vec, vec::Vec};
 use crate::{
     cipherstate::{CipherState, CipherStates},
     constants::{MAXDHLEN, MAXMSGLEN, PSKLEN, TAGLEN},
-    error::{Error, InitStage, StateProblem},
+    error::{Error, InitStage, PatternProblem, Prerequisite, StateProblem},
     params::{DhToken, HandshakeTokens, MessagePatterns, NoiseParams, Token},
     stateless_transportstate::StatelessTransportState,
-    symmetricstate::SymmetricState,
-    transportstate::TransportState,
+    symmetricstate::{Checkpoint, SymmetricState},
+    transportstate::{hash_once, TransportState},
     types::{Dh, Hash, Random},
-    utils::Toggle,
+    utils::{read_framed, redacted, write_framed, Toggle},
 };
-use std::{
+use core::{
     convert::{TryFrom, TryInto},
     fmt,
 };
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
 
 /// A state machine encompassing the handshake phase of a Noise session.
 ///
@@ -30,14 +33,16 @@ pub struct HandshakeState {
     pub(crate) rng:              Box<dyn Random>,
     pub(crate) symmetricstate:   SymmetricState,
     pub(crate) cipherstates:     CipherStates,
+    pub(crate) transport_hasher: Box<dyn Hash>,
     pub(crate) s:                Toggle<Box<dyn Dh>>,
     pub(crate) e:                Toggle<Box<dyn Dh>>,
     pub(crate) fixed_ephemeral:  bool,
+    e_pregenerated:              bool,
     pub(crate) rs:               Toggle<[u8; MAXDHLEN]>,
     pub(crate) re:               Toggle<[u8; MAXDHLEN]>,
     pub(crate) initiator:        bool,
     pub(crate) params:           NoiseParams,
-    pub(crate) psks:             [Option<[u8; PSKLEN]>; 10],
+    pub(crate) psks:             Zeroizing<[Option<[u8; PSKLEN]>; 10]>,
     #[cfg(feature = "hfs")]
     pub(crate) kem:              Option<Box<dyn Kem>>,
     #[cfg(feature = "hfs")]
@@ -45,8 +50,17 @@ pub struct HandshakeState {
     pub(crate) my_turn:          bool,
     pub(crate) message_patterns: MessagePatterns,
     pub(crate) pattern_position: usize,
+    pub(crate) max_len:          usize,
+    pub(crate) on_step:          Option<StepHook>,
+    hkdf_context:                Option<Vec<u8>>,
+    after_prologue:              Checkpoint,
 }
 
+/// Called with the handshake hash after each message, see [`Builder::on_handshake_step()`].
+///
+/// [`Builder::on_handshake_step()`]: crate::Builder::on_handshake_step
+pub(crate) type StepHook = Box<dyn Fn(&[u8]) + Send + Sync>;
+
 impl HandshakeState {
     #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
@@ -56,13 +70,16 @@ impl HandshakeState {
         s: Toggle<Box<dyn Dh>>,
         e: Toggle<Box<dyn Dh>>,
         fixed_ephemeral: bool,
+        e_pregenerated: bool,
         rs: Toggle<[u8; MAXDHLEN]>,
         re: Toggle<[u8; MAXDHLEN]>,
         initiator: bool,
         params: NoiseParams,
         psks: [Option<[u8; PSKLEN]>; 10],
-        prologue: &[u8],
+        hkdf_context: Option<&[u8]>,
+        prologue: impl FnOnce(&mut dyn Hash) -> Result<(), Error>,
         cipherstates: CipherStates,
+        transport_hasher: Box<dyn Hash>,
     ) -> Result<HandshakeState, Error> {
         if (s.is_on() && e.is_on() && s.pub_len() != e.pub_len())
             || (s.is_on() && rs.is_on() && s.pub_len() > rs.len())
@@ -76,71 +93,26 @@ impl HandshakeState {
         let mut symmetricstate = SymmetricState::new(cipherstate, hasher);
 
         symmetricstate.initialize(&params.name);
-        symmetricstate.mix_hash(prologue);
-
-        let dh_len = s.pub_len();
-        if initiator {
-            for token in tokens.premsg_pattern_i {
-                symmetricstate.mix_hash(
-                    match *token {
-                        Token::S => &s,
-                        Token::E => &e,
-                        _ => unreachable!(),
-                    }
-                    .get()
-                    .ok_or(StateProblem::MissingKeyMaterial)?
-                    .pubkey(),
-                );
-            }
-            for token in tokens.premsg_pattern_r {
-                symmetricstate.mix_hash(
-                    &match *token {
-                        Token::S => &rs,
-                        Token::E => &re,
-                        _ => unreachable!(),
-                    }
-                    .get()
-                    .ok_or(StateProblem::MissingKeyMaterial)?[..dh_len],
-                );
-            }
-        } else {
-            for token in tokens.premsg_pattern_i {
-                symmetricstate.mix_hash(
-                    &match *token {
-                        Token::S => &rs,
-                        Token::E => &re,
-                        _ => unreachable!(),
-                    }
-                    .get()
-                    .ok_or(StateProblem::MissingKeyMaterial)?[..dh_len],
-                );
-            }
-            for token in tokens.premsg_pattern_r {
-                symmetricstate.mix_hash(
-                    match *token {
-                        Token::S => &s,
-                        Token::E => &e,
-                        _ => unreachable!(),
-                    }
-                    .get()
-                    .ok_or(StateProblem::MissingKeyMaterial)?
-                    .pubkey(),
-                );
-            }
+        if let Some(context) = hkdf_context {
+            symmetricstate.mix_context(context);
         }
+        symmetricstate.mix_hash_with(prologue)?;
+        let after_prologue = symmetricstate.checkpoint();
 
-        Ok(HandshakeState {
+        let mut hs = HandshakeState {
             rng,
             symmetricstate,
             cipherstates,
+            transport_hasher,
             s,
             e,
             fixed_ephemeral,
+            e_pregenerated,
             rs,
             re,
             initiator,
             params,
-            psks,
+            psks: Zeroizing::new(psks),
             #[cfg(feature = "hfs")]
             kem: None,
             #[cfg(feature = "hfs")]
@@ -148,10 +120,61 @@ impl HandshakeState {
             my_turn: initiator,
             message_patterns: tokens.msg_patterns,
             pattern_position: 0,
-        })
+            max_len: MAXMSGLEN,
+            on_step: None,
+            hkdf_context: hkdf_context.map(<[u8]>::to_vec),
+            after_prologue,
+        };
+        hs.mix_premessages(tokens.premsg_pattern_i, tokens.premsg_pattern_r)?;
+        Ok(hs)
     }
 
-    pub(crate) fn dh_len(&self) -> usize {
+    fn log_finished(&self) {
+        if self.is_handshake_finished() {
+            log_debug!("{} handshake finished as {}", self.params.name, self.role());
+        }
+    }
+
+    fn role(&self) -> &'static str {
+        if self.initiator {
+            "initiator"
+        } else {
+            "responder"
+        }
+    }
+
+    fn notify_step(&self) {
+        if let Some(on_step) = &self.on_step {
+            on_step(self.symmetricstate.handshake_hash());
+        }
+    }
+
+    /// Mix the public keys named by the pre-message patterns into the handshake hash.
+    fn mix_premessages(&mut self, premsg_i: &[Token], premsg_r: &[Token]) -> Result<(), Error> {
+        let dh_len = self.dh_len();
+        let is_psk = self.params.handshake.is_psk();
+        for (tokens, local) in [(premsg_i, self.initiator), (premsg_r, !self.initiator)] {
+            for token in tokens {
+                let pubkey = match (*token, local) {
+                    (Token::S, true) => self.s.get().map(|s| s.pubkey()),
+                    (Token::E, true) => self.e.get().map(|e| e.pubkey()),
+                    (Token::S, false) => self.rs.get().map(|rs| &rs[..dh_len]),
+                    (Token::E, false) => self.re.get().map(|re| &re[..dh_len]),
+                    _ => unreachable!(),
+                }
+                .ok_or(StateProblem::MissingKeyMaterial)?;
+                self.symmetricstate.mix_hash(pubkey);
+                if *token == Token::E && is_psk {
+                    self.symmetricstate.mix_key(pubkey)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the length of public keys and DH outputs for the negotiated DH function, e.g. 32
+    /// for `25519` and 56 for `448`.
+    pub fn dh_len(&self) -> usize {
         self.s.pub_len()
     }
 
@@ -162,16 +185,24 @@ impl HandshakeState {
 
     fn dh(&self, token: &DhToken) -> Result<[u8; MAXDHLEN], Error> {
         let mut dh_out = [0u8; MAXDHLEN];
-        let (dh, key) = match (token, self.is_initiator()) {
-            (DhToken::Ee, _) => (&self.e, &self.re),
-            (DhToken::Ss, _) => (&self.s, &self.rs),
-            (DhToken::Se, true) | (DhToken::Es, false) => (&self.s, &self.re),
-            (DhToken::Es, true) | (DhToken::Se, false) => (&self.e, &self.rs),
+        let (dh, key, remote_e) = match (token, self.is_initiator()) {
+            (DhToken::Ee, _) => (&self.e, &self.re, true),
+            (DhToken::Ss, _) => (&self.s, &self.rs, false),
+            (DhToken::Se, true) | (DhToken::Es, false) => (&self.s, &self.re, true),
+            (DhToken::Es, true) | (DhToken::Se, false) => (&self.e, &self.rs, false),
         };
         if !(dh.is_on() && key.is_on()) {
             bail!(StateProblem::MissingKeyMaterial);
         }
-        dh.dh(&**key, &mut dh_out).map_err(|_| Error::Dh)?;
+        if remote_e {
+            // The remote ephemeral is kept as sent, e.g. Elligator2-encoded, for the hash.
+            let mut decoded = [0u8; MAXDHLEN];
+            let len = self.e.pub_len();
+            self.e.decode_pubkey(&key[..len], &mut decoded[..]).map_err(|_| Error::Dh)?;
+            dh.dh(&decoded[..], &mut dh_out).map_err(|_| Error::Dh)?;
+        } else {
+            dh.dh(&**key, &mut dh_out).map_err(|_| Error::Dh)?;
+        }
         Ok(dh_out)
     }
 
@@ -194,6 +225,76 @@ impl HandshakeState {
         self.symmetricstate.has_key()
     }
 
+    /// Check whether the payload of the last message read was encrypted.
+    ///
+    /// Returns `false` if no message has been read yet (since the last fallback, if any).
+    pub fn was_read_payload_encrypted(&self) -> bool {
+        let is_psk = self.params.handshake.is_psk();
+        self.read_tokens().any(|token| match token {
+            Token::E => is_psk,
+            Token::Psk(_) | Token::Dh(_) => true,
+            #[cfg(feature = "hfs")]
+            Token::Ekem1 => true,
+            _ => false,
+        })
+    }
+
+    /// Check whether the payload of the last message read had forward secrecy, i.e. its key
+    /// depended on a DH between both parties' ephemeral keys.
+    ///
+    /// The payload of the first message of e.g. `IK` (0-RTT data) doesn't: it can be
+    /// decrypted by anyone who later learns the responder's static private key.
+    ///
+    /// Returns `false` if no message has been read yet (since the last fallback, if any).
+    pub fn was_read_payload_forward_secret(&self) -> bool {
+        self.read_tokens().any(|token| *token == Token::Dh(DhToken::Ee))
+    }
+
+    /// Check whether the payload of the last message read could have been replayed, i.e. its
+    /// key didn't depend on a DH with our ephemeral key.
+    ///
+    /// This is the case for the first message of every handshake, including the 0-RTT data
+    /// of e.g. `IK`: a recorded message is accepted again by any responder with the same keys.
+    /// Don't act on such a payload in a way that mustn't happen twice before the handshake
+    /// is finished.
+    ///
+    /// Returns `false` if no message has been read yet (since the last fallback, if any).
+    pub fn was_read_payload_replayable(&self) -> bool {
+        let local_e = if self.initiator { DhToken::Es } else { DhToken::Se };
+        self.last_read().is_some()
+            && !self.read_tokens().any(|token| {
+                *token == Token::Dh(DhToken::Ee) || *token == Token::Dh(local_e)
+            })
+    }
+
+    /// Check whether the remote party has proven it holds the private key of its static key
+    /// (see [`get_remote_static()`](#method.get_remote_static)), i.e. a message read so far was
+    /// encrypted with a key depending on a DH with the remote static key.
+    ///
+    /// This is never the case for patterns without a remote static key, like `NN`, and becomes
+    /// `true` for e.g. `XX` once the message carrying the remote static key has been read. It
+    /// doesn't check that the key is one you expected: that's still up to you.
+    ///
+    /// Returns `false` if no message has been read yet (since the last fallback, if any).
+    pub fn is_remote_authenticated(&self) -> bool {
+        let remote_s = if self.initiator { DhToken::Es } else { DhToken::Se };
+        self.read_tokens().any(|token| {
+            *token == Token::Dh(remote_s) || *token == Token::Dh(DhToken::Ss)
+        })
+    }
+
+    /// The pattern position of the last message read. Turns alternate, so it's the previous
+    /// message if it's our turn, and the one before otherwise.
+    fn last_read(&self) -> Option<usize> {
+        self.pattern_position.checked_sub(if self.my_turn { 1 } else { 2 })
+    }
+
+    /// The tokens of all messages up to and including the last one read.
+    fn read_tokens(&self) -> impl Iterator<Item = &Token> {
+        let end = self.last_read().map_or(0, |position| position + 1);
+        self.message_patterns[..end].iter().flatten()
+    }
+
     /// Construct a message from `payload` (and pending handshake tokens if in handshake state),
     /// and writes it to the `message` buffer.
     ///
@@ -202,16 +303,33 @@ impl HandshakeState {
     /// # Errors
     ///
     /// Will result in `Error::Input` if the size of the output exceeds the max message
-    /// length in the Noise Protocol (65535 bytes).
+    /// length in the Noise Protocol (65535 bytes, unless changed with
+    /// [`Builder::max_message_len()`]).
+    ///
+    /// [`Builder::max_message_len()`]: crate::Builder::max_message_len
     pub fn write_message(&mut self, payload: &[u8], message: &mut [u8]) -> Result<usize, Error> {
         let checkpoint = self.symmetricstate.checkpoint();
         match self._write_message(payload, message) {
             Ok(res) => {
+                log_debug!(
+                    "wrote handshake message {} of {} ({} bytes, {} byte payload)",
+                    self.pattern_position + 1,
+                    self.message_patterns.len(),
+                    res,
+                    payload.len()
+                );
                 self.pattern_position += 1;
                 self.my_turn = false;
+                self.log_finished();
+                self.notify_step();
                 Ok(res)
             },
             Err(err) => {
+                log_debug!(
+                    "failed to write handshake message {}: {:?}",
+                    self.pattern_position + 1,
+                    err
+                );
                 self.symmetricstate.restore(checkpoint);
                 Err(err)
             },
@@ -223,25 +341,30 @@ impl HandshakeState {
             bail!(StateProblem::NotTurnToWrite);
         } else if self.pattern_position >= self.message_patterns.len() {
             bail!(StateProblem::HandshakeAlreadyFinished);
+        } else if payload.len() > self.max_len {
+            bail!(Error::Input);
         }
 
         let mut byte_index = 0;
         for token in self.message_patterns[self.pattern_position].iter() {
+            log_trace!("writing token {:?}", token);
             match token {
                 Token::E => {
                     if byte_index + self.e.pub_len() > message.len() {
                         bail!(Error::Input)
                     }
 
-                    if !self.fixed_ephemeral {
+                    // A key generated when building is used once, like a freshly generated one.
+                    if !self.fixed_ephemeral && !self.e_pregenerated {
                         self.e.generate(&mut *self.rng);
                     }
+                    self.e_pregenerated = false;
                     let pubkey = self.e.pubkey();
                     message[byte_index..byte_index + pubkey.len()].copy_from_slice(pubkey);
                     byte_index += pubkey.len();
                     self.symmetricstate.mix_hash(pubkey);
                     if self.params.handshake.is_psk() {
-                        self.symmetricstate.mix_key(pubkey);
+                        self.symmetricstate.mix_key(pubkey)?;
                     }
                     self.e.enable();
                 },
@@ -258,7 +381,7 @@ impl HandshakeState {
                 },
                 Token::Psk(n) => match self.psks[*n as usize] {
                     Some(psk) => {
-                        self.symmetricstate.mix_key_and_hash(&psk);
+                        self.symmetricstate.mix_key_and_hash(&psk)?;
                     },
                     None => {
                         bail!(StateProblem::MissingPsk);
@@ -266,7 +389,7 @@ impl HandshakeState {
                 },
                 Token::Dh(t) => {
                     let dh_out = self.dh(t)?;
-                    self.symmetricstate.mix_key(&dh_out[..self.dh_len()]);
+                    self.symmetricstate.mix_key(&dh_out[..self.dh_len()])?;
                 },
                 #[cfg(feature = "hfs")]
                 Token::E1 => {
@@ -301,21 +424,21 @@ impl HandshakeState {
                         &ciphertext[..kem.ciphertext_len()],
                         &mut message[byte_index..],
                     )?;
-                    self.symmetricstate.mix_key(&kem_output[..kem.shared_secret_len()]);
+                    self.symmetricstate.mix_key(&kem_output[..kem.shared_secret_len()])?;
                 },
             }
         }
 
-        if byte_index + payload.len() + TAGLEN > message.len() {
+        let tag_len = if self.symmetricstate.has_key() { TAGLEN } else { 0 };
+        if byte_index + payload.len() + TAGLEN > message.len()
+            || byte_index + payload.len() + tag_len > self.max_len
+        {
             bail!(Error::Input);
         }
         byte_index +=
             self.symmetricstate.encrypt_and_mix_hash(payload, &mut message[byte_index..])?;
-        if byte_index > MAXMSGLEN {
-            bail!(Error::Input);
-        }
         if self.pattern_position == (self.message_patterns.len() - 1) {
-            self.symmetricstate.split(&mut self.cipherstates.0, &mut self.cipherstates.1);
+            self.symmetricstate.split(&mut self.cipherstates.0, &mut self.cipherstates.1)?;
         }
         Ok(byte_index)
     }
@@ -324,23 +447,42 @@ impl HandshakeState {
     ///
     /// Returns the size of the payload written to `payload`.
     ///
-    /// # Errors
-    ///
-    /// Will result in `Error::Decrypt` if the contents couldn't be decrypted and/or the
-    /// authentication tag didn't verify.
+    /// Whatever follows the message's tokens is its payload, so bytes appended to a message
+    /// are never silently dropped: once a key has been established they break the tag. Before
+    /// that (e.g. in the first message of `XX`) they're returned as cleartext payload, and as
+    /// they're mixed into the handshake hash, the next encrypted message fails to decrypt. If
+    /// the protocol expects no payload at that point, check that the returned size is 0.
     ///
-    /// # Panics
+    /// # Errors
     ///
-    /// This function will panic if there is no key, or if there is a nonce overflow.
+    /// Will result in `Error::Decrypt` if the authentication tag didn't verify, i.e. the
+    /// message was corrupted or tampered with, and in `Error::Input` if `payload` is too
+    /// small to hold the decrypted contents. On `Error::Decrypt`, the part of `payload` the
+    /// message would have filled is zeroed.
     pub fn read_message(&mut self, message: &[u8], payload: &mut [u8]) -> Result<usize, Error> {
         let checkpoint = self.symmetricstate.checkpoint();
         match self._read_message(message, payload) {
             Ok(res) => {
+                log_debug!(
+                    "read handshake message {} of {} ({} bytes, {} byte payload)",
+                    self.pattern_position + 1,
+                    self.message_patterns.len(),
+                    message.len(),
+                    res
+                );
                 self.pattern_position += 1;
                 self.my_turn = true;
+                self.log_finished();
+                self.notify_step();
                 Ok(res)
             },
             Err(err) => {
+                log_debug!(
+                    "failed to read handshake message {} ({} bytes): {:?}",
+                    self.pattern_position + 1,
+                    message.len(),
+                    err
+                );
                 self.symmetricstate.restore(checkpoint);
                 Err(err)
             },
@@ -348,7 +490,7 @@ impl HandshakeState {
     }
 
     fn _read_message(&mut self, message: &[u8], payload: &mut [u8]) -> Result<usize, Error> {
-        if message.len() > MAXMSGLEN {
+        if message.len() > self.max_len {
             bail!(Error::Input);
         } else if self.my_turn {
             bail!(StateProblem::NotTurnToRead);
@@ -360,6 +502,7 @@ impl HandshakeState {
         let dh_len = self.dh_len();
         let mut ptr = message;
         for token in self.message_patterns[self.pattern_position].iter() {
+            log_trace!("reading token {:?}", token);
             match token {
                 Token::E => {
                     if ptr.len() < dh_len {
@@ -369,7 +512,7 @@ impl HandshakeState {
                     ptr = &ptr[dh_len..];
                     self.symmetricstate.mix_hash(&self.re[..dh_len]);
                     if self.params.handshake.is_psk() {
-                        self.symmetricstate.mix_key(&self.re[..dh_len]);
+                        self.symmetricstate.mix_key(&self.re[..dh_len])?;
                     }
                     self.re.enable();
                 },
@@ -389,14 +532,15 @@ impl HandshakeState {
                         ptr = &ptr[dh_len..];
                         temp
                     };
-                    self.symmetricstate
-                        .decrypt_and_mix_hash(data, &mut self.rs[..dh_len])
-                        .map_err(|_| Error::Decrypt)?;
+                    // The received key is never compared to an expected one here: it's
+                    // authenticated by the AEAD tag and the DHs that follow, so there's no
+                    // secret-dependent `==` to leak timing through.
+                    self.symmetricstate.decrypt_and_mix_hash(data, &mut self.rs[..dh_len])?;
                     self.rs.enable();
                 },
                 Token::Psk(n) => match self.psks[*n as usize] {
                     Some(psk) => {
-                        self.symmetricstate.mix_key_and_hash(&psk);
+                        self.symmetricstate.mix_key_and_hash(&psk)?;
                     },
                     None => {
                         bail!(StateProblem::MissingPsk);
@@ -404,7 +548,7 @@ impl HandshakeState {
                 },
                 Token::Dh(t) => {
                     let dh_out = self.dh(t)?;
-                    self.symmetricstate.mix_key(&dh_out[..self.dh_len()]);
+                    self.symmetricstate.mix_key(&dh_out[..self.dh_len()])?;
                 },
                 #[cfg(feature = "hfs")]
                 Token::E1 => {
@@ -419,8 +563,7 @@ impl HandshakeState {
                     }
                     let mut kem_re = [0; MAXKEMPUBLEN];
                     self.symmetricstate
-                        .decrypt_and_mix_hash(&ptr[..read_len], &mut kem_re[..kem.pub_len()])
-                        .map_err(|_| Error::Decrypt)?;
+                        .decrypt_and_mix_hash(&ptr[..read_len], &mut kem_re[..kem.pub_len()])?;
                     self.kem_re = Some(kem_re);
                     ptr = &ptr[read_len..];
                 },
@@ -437,27 +580,93 @@ impl HandshakeState {
                     }
                     let mut ciphertext_buf = [0; MAXKEMCTLEN];
                     let ciphertext = &mut ciphertext_buf[..kem.ciphertext_len()];
-                    self.symmetricstate
-                        .decrypt_and_mix_hash(&ptr[..read_len], ciphertext)
-                        .map_err(|_| Error::Decrypt)?;
+                    self.symmetricstate.decrypt_and_mix_hash(&ptr[..read_len], ciphertext)?;
                     let mut kem_output_buf = [0; MAXKEMSSLEN];
                     let kem_output = &mut kem_output_buf[..kem.shared_secret_len()];
                     kem.decapsulate(ciphertext, kem_output).map_err(|_| Error::Kem)?;
-                    self.symmetricstate.mix_key(&kem_output[..kem.shared_secret_len()]);
+                    self.symmetricstate.mix_key(&kem_output[..kem.shared_secret_len()])?;
                     ptr = &ptr[read_len..];
                 },
             }
         }
 
-        self.symmetricstate.decrypt_and_mix_hash(ptr, payload).map_err(|_| Error::Decrypt)?;
+        self.symmetricstate.decrypt_and_mix_hash(ptr, payload)?;
         if last {
-            self.symmetricstate.split(&mut self.cipherstates.0, &mut self.cipherstates.1);
+            self.symmetricstate.split(&mut self.cipherstates.0, &mut self.cipherstates.1)?;
         }
         let payload_len =
             if self.symmetricstate.has_key() { ptr.len() - TAGLEN } else { ptr.len() };
         Ok(payload_len)
     }
 
+    /// Like [`write_message()`], but allocates and returns the message.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`write_message()`].
+    ///
+    /// [`write_message()`]: #method.write_message
+    pub fn write_message_vec(&mut self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        // Handshake tokens take well under MAXMSGLEN, so that much on top of the payload is
+        // enough room even when the limit was raised.
+        let mut message = vec![0u8; (payload.len() + MAXMSGLEN).min(self.max_len.max(MAXMSGLEN))];
+        let len = self.write_message(payload, &mut message)?;
+        message.truncate(len);
+        Ok(message)
+    }
+
+    /// Like [`read_message()`], but allocates and returns the payload.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`read_message()`].
+    ///
+    /// [`read_message()`]: #method.read_message
+    pub fn read_message_vec(&mut self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut payload = vec![0u8; message.len()];
+        let len = self.read_message(message, &mut payload)?;
+        payload.truncate(len);
+        Ok(payload)
+    }
+
+    /// Like [`write_message()`], but appends the message to `out` as a frame: its length as a
+    /// 2-byte big-endian integer, followed by the message itself.
+    ///
+    /// Returns the number of bytes appended. On error, `out` is left as it was.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Input` if the message would exceed the max message length in
+    /// the Noise Protocol (65535 bytes).
+    ///
+    /// [`write_message()`]: #method.write_message
+    pub fn write_message_framed(
+        &mut self,
+        payload: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<usize, Error> {
+        write_framed(out, MAXMSGLEN, |message| self.write_message(payload, message))
+    }
+
+    /// Like [`read_message()`], but reads one frame written by [`write_message_framed()`]
+    /// from the front of `input`.
+    ///
+    /// Returns the bytes of `input` following the frame, and the payload.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Input` if `input` is shorter than the frame claims to be, and
+    /// otherwise fails like [`read_message()`].
+    ///
+    /// [`read_message()`]: #method.read_message
+    /// [`write_message_framed()`]: #method.write_message_framed
+    pub fn read_message_framed<'a>(
+        &mut self,
+        input: &'a [u8],
+    ) -> Result<(&'a [u8], Vec<u8>), Error> {
+        read_framed(input, |message, payload| self.read_message(message, payload))
+    }
+
     /// Set the preshared key at the specified location. It is up to the caller
     /// to correctly set the location based on the specified handshake - Snow
     /// won't stop you from placing a PSK in an unused slot.
@@ -472,7 +681,7 @@ impl HandshakeState {
 
         let mut new_psk = [0u8; PSKLEN];
         new_psk.copy_from_slice(key);
-        self.psks[location as usize] = Some(new_psk);
+        self.psks[location] = Some(new_psk);
 
         Ok(())
     }
@@ -483,10 +692,104 @@ impl HandshakeState {
     /// doesn't necessitate a remote static key, *or* if the remote
     /// static key is not yet known (as can be the case in the `XX`
     /// pattern, for example).
+    ///
+    /// If you pin the remote key, compare it in constant time (e.g. with
+    /// `subtle::ConstantTimeEq`) rather than with `==`.
     pub fn get_remote_static(&self) -> Option<&[u8]> {
         self.rs.get().map(|rs| &rs[..self.dh_len()])
     }
 
+    /// Replace the remote static public key given to the builder, e.g. when a negotiation
+    /// layer picks the peer only after the handshake object exists.
+    ///
+    /// This only works for a key the pattern takes as a pre-message (such as the responder's
+    /// key in `IK`), and only before the first handshake message: the key is hashed in from the
+    /// start and consumed by that message's DHs.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::State` with `RemoteStaticFixed` if the pattern receives the
+    /// remote static key in a message or a message has already been written or read,
+    /// `Error::Input` if `key` has the wrong length, and `Error::Prereq` if it's our own
+    /// static public key.
+    pub fn set_remote_static(&mut self, key: &[u8]) -> Result<(), Error> {
+        let tokens = HandshakeTokens::try_from(&self.params.handshake)?;
+        let remote_premsg =
+            if self.initiator { tokens.premsg_pattern_r } else { tokens.premsg_pattern_i };
+        if self.pattern_position > 0 || !remote_premsg.contains(&Token::S) {
+            bail!(StateProblem::RemoteStaticFixed);
+        } else if key.len() != self.dh_len() {
+            bail!(Error::Input);
+        } else if let Some(s) = self.s.get() {
+            if bool::from(s.pubkey().ct_eq(key)) {
+                bail!(Prerequisite::RemoteIsLocalStatic);
+            }
+        }
+
+        self.rs[..key.len()].copy_from_slice(key);
+        self.rs.enable();
+        self.symmetricstate.restore(self.after_prologue.clone());
+        self.mix_premessages(tokens.premsg_pattern_i, tokens.premsg_pattern_r)
+    }
+
+    /// Go back to the start of the handshake (or of the fallback handshake, after
+    /// [`into_fallback()`](#method.into_fallback)), keeping the keys and PSKs it was built with.
+    ///
+    /// This lets a responder whose first message turned out to be bogus, e.g. from a peer
+    /// speaking another protocol, read the next one without building a new state. Remote keys
+    /// received in messages are forgotten, and a new ephemeral key is generated for the next
+    /// message that sends one, even if the first was generated eagerly. Only a key fixed for
+    /// testing is reused.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::State` if a key the pattern takes as a pre-message is missing,
+    /// which can't happen for a state that was built successfully.
+    pub fn reset_handshake(&mut self) -> Result<(), Error> {
+        let tokens = HandshakeTokens::try_from(&self.params.handshake)?;
+        let (local_premsg, remote_premsg) = if self.initiator {
+            (tokens.premsg_pattern_i, tokens.premsg_pattern_r)
+        } else {
+            (tokens.premsg_pattern_r, tokens.premsg_pattern_i)
+        };
+        if !remote_premsg.contains(&Token::S) {
+            self.rs.disable();
+        }
+        if !remote_premsg.contains(&Token::E) {
+            self.re.disable();
+        }
+        if !local_premsg.contains(&Token::E) && !self.fixed_ephemeral {
+            self.e.disable();
+            self.e_pregenerated = false;
+        }
+        #[cfg(feature = "hfs")]
+        {
+            self.kem_re = None;
+        }
+
+        log_debug!("resetting {} handshake as {}", self.params.name, self.role());
+        self.symmetricstate.restore(self.after_prologue.clone());
+        self.mix_premessages(tokens.premsg_pattern_i, tokens.premsg_pattern_r)?;
+        self.my_turn = self.initiator;
+        self.pattern_position = 0;
+        Ok(())
+    }
+
+    /// Get our ephemeral public key, e.g. for logging.
+    ///
+    /// Returns `None` until the ephemeral key has been generated, which happens when writing
+    /// the first message that sends it (for an initiator, usually the first message), or
+    /// when building with [`Builder::eager_ephemeral()`].
+    ///
+    /// [`Builder::eager_ephemeral()`]: crate::Builder::eager_ephemeral
+    pub fn get_local_ephemeral(&self) -> Option<&[u8]> {
+        if self.e.is_on() || self.fixed_ephemeral || self.e_pregenerated {
+            Some(self.e.pubkey())
+        } else {
+            None
+        }
+    }
+
     /// Get the handshake hash.
     ///
     /// Returns a slice of length `Hasher.hash_len()` (i.e. HASHLEN for the chosen Hash function).
@@ -494,6 +797,20 @@ impl HandshakeState {
         self.symmetricstate.handshake_hash()
     }
 
+    /// Hash `data` with the hash function negotiated for this session, like
+    /// [`TransportState::hash()`].
+    ///
+    /// [`TransportState::hash()`]: crate::TransportState::hash
+    pub fn hash(&mut self, data: &[u8]) -> Vec<u8> {
+        hash_once(&mut *self.transport_hasher, data)
+    }
+
+    /// Get the Noise parameters this handshake was built with, e.g. to find out which DH,
+    /// cipher, and hash functions are in use.
+    pub fn get_params(&self) -> &NoiseParams {
+        &self.params
+    }
+
     /// Check if this session was started with the "initiator" role.
     pub fn is_initiator(&self) -> bool {
         self.initiator
@@ -504,11 +821,94 @@ impl HandshakeState {
         self.pattern_position == self.message_patterns.len()
     }
 
+    /// Get the number of handshake messages still to be sent or received, including the
+    /// current one. Use [`is_my_turn()`](#method.is_my_turn) to tell which side sends next.
+    pub fn handshake_messages_remaining(&self) -> usize {
+        self.message_patterns.len() - self.pattern_position
+    }
+
+    /// Get the length of the next handshake message to be read, assuming an empty payload.
+    ///
+    /// The tokens of each handshake message have a fixed size, so this is the number of bytes
+    /// to accumulate from a stream before calling [`read_message()`]. A non-empty payload adds
+    /// its own length on top, which has to be communicated by the application (e.g. with
+    /// [`read_message_framed()`]).
+    ///
+    /// Returns `None` if it's our turn to write or the handshake is finished.
+    ///
+    /// [`read_message()`]: #method.read_message
+    /// [`read_message_framed()`]: #method.read_message_framed
+    pub fn next_message_len(&self) -> Option<usize> {
+        if self.my_turn || self.is_handshake_finished() {
+            return None;
+        }
+        self.current_message_len(0)
+    }
+
+    /// Get the length of the next handshake message that [`write_message()`] will produce for
+    /// a payload of `payload_len` bytes.
+    ///
+    /// This counts the ephemeral and static keys sent in the current message, the tags of
+    /// whatever gets encrypted, and the payload, so an output buffer can be sized up front.
+    ///
+    /// Returns `None` if it's not our turn to write or the handshake is finished.
+    ///
+    /// [`write_message()`]: #method.write_message
+    pub fn next_write_len(&self, payload_len: usize) -> Option<usize> {
+        if !self.my_turn || self.is_handshake_finished() {
+            return None;
+        }
+        self.current_message_len(payload_len)
+    }
+
+    /// The length of the message at the current pattern position, with a payload of
+    /// `payload_len` bytes.
+    fn current_message_len(&self, payload_len: usize) -> Option<usize> {
+        let dh_len = self.dh_len();
+        let mut has_key = self.symmetricstate.has_key();
+        let tag_len = |has_key| if has_key { TAGLEN } else { 0 };
+        let mut len = 0;
+        for token in self.message_patterns[self.pattern_position].iter() {
+            match token {
+                Token::E => {
+                    len += dh_len;
+                    has_key |= self.params.handshake.is_psk();
+                },
+                Token::S => len += dh_len + tag_len(has_key),
+                Token::Psk(_) | Token::Dh(_) => has_key = true,
+                #[cfg(feature = "hfs")]
+                Token::E1 => len += self.kem.as_ref()?.pub_len() + tag_len(has_key),
+                #[cfg(feature = "hfs")]
+                Token::Ekem1 => {
+                    len += self.kem.as_ref()?.ciphertext_len() + tag_len(has_key);
+                    has_key = true;
+                },
+            }
+        }
+        Some(len + payload_len + tag_len(has_key))
+    }
+
     /// Check whether it is our turn to send in the handshake state machine
+    ///
+    /// This stays `true` after reading the last handshake message, see
+    /// [`is_my_turn_to_write()`](#method.is_my_turn_to_write) for a check that doesn't.
     pub fn is_my_turn(&self) -> bool {
         self.my_turn
     }
 
+    /// Check whether the next call should be [`write_message()`] rather than
+    /// [`read_message()`]. Unlike [`is_my_turn()`], this is `false` once the handshake is
+    /// finished, so it can drive a handshake loop on its own: write while it's `true`, read
+    /// otherwise, until [`is_handshake_finished()`].
+    ///
+    /// [`write_message()`]: #method.write_message
+    /// [`read_message()`]: #method.read_message
+    /// [`is_my_turn()`]: #method.is_my_turn
+    /// [`is_handshake_finished()`]: #method.is_handshake_finished
+    pub fn is_my_turn_to_write(&self) -> bool {
+        self.my_turn && !self.is_handshake_finished()
+    }
+
     /// Perform the split calculation and return the resulting keys.
     ///
     /// This returns raw key material so it should be used with care. The "risky-raw-split"
@@ -520,6 +920,66 @@ impl HandshakeState {
         (output.0[..CIPHERKEYLEN].try_into().unwrap(), output.1[..CIPHERKEYLEN].try_into().unwrap())
     }
 
+    /// Convert this `HandshakeState` into the fallback handshake described by `params`
+    /// (for example `XXfallback` after a failed `IK` attempt, as in Noise Pipes).
+    ///
+    /// The ephemeral key from the first handshake message is carried over as a pre-message, so
+    /// the initiator must already have written that message and the responder must have at
+    /// least tried to read it. The roles are swapped: the former responder becomes the initiator
+    /// of the fallback handshake and sends the next message.
+    ///
+    /// See: [The fallback modifier](http://noiseprotocol.org/noise.html#the-fallback-modifier)
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Pattern` if `params` doesn't use the `fallback` modifier,
+    /// `Error::Input` if `params` doesn't share this handshake's DH, cipher, and hash choices,
+    /// and `Error::State` if the first message's ephemeral key isn't available.
+    pub fn into_fallback(
+        mut self,
+        params: NoiseParams,
+        prologue: &[u8],
+    ) -> Result<HandshakeState, Error> {
+        self.fall_back(params, prologue)?;
+        Ok(self)
+    }
+
+    /// [`into_fallback()`](#method.into_fallback) in place. On error the state may be left
+    /// half converted and shouldn't be used further.
+    pub(crate) fn fall_back(&mut self, params: NoiseParams, prologue: &[u8]) -> Result<(), Error> {
+        if !params.handshake.is_fallback() {
+            bail!(PatternProblem::UnsupportedModifier);
+        } else if params.dh != self.params.dh
+            || params.cipher != self.params.cipher
+            || params.hash != self.params.hash
+        {
+            bail!(Error::Input);
+        }
+
+        let tokens = HandshakeTokens::try_from(&params.handshake)?;
+        self.initiator = !self.initiator;
+        let remote_premsg =
+            if self.initiator { tokens.premsg_pattern_r } else { tokens.premsg_pattern_i };
+        if !remote_premsg.contains(&Token::S) {
+            self.rs.disable();
+        }
+
+        log_debug!("falling back from {} to {}", self.params.name, params.name);
+        self.symmetricstate.initialize(&params.name);
+        if let Some(context) = &self.hkdf_context {
+            self.symmetricstate.mix_context(context);
+        }
+        self.symmetricstate.mix_hash(prologue);
+        self.after_prologue = self.symmetricstate.checkpoint();
+        self.params = params;
+        self.mix_premessages(tokens.premsg_pattern_i, tokens.premsg_pattern_r)?;
+
+        self.my_turn = self.initiator;
+        self.message_patterns = tokens.msg_patterns;
+        self.pattern_position = 0;
+        Ok(())
+    }
+
     /// Convert this `HandshakeState` into a `TransportState` with an internally stored nonce.
     pub fn into_transport_mode(self) -> Result<TransportState, Error> {
         self.try_into()
@@ -533,6 +993,16 @@ impl HandshakeState {
 
 impl fmt::Debug for HandshakeState {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt.debug_struct("HandshakeState").finish()
+        fmt.debug_struct("HandshakeState")
+            .field("params", &self.params.name)
+            .field("initiator", &self.initiator)
+            .field("my_turn", &self.my_turn)
+            .field("pattern_position", &self.pattern_position)
+            .field("handshake_finished", &self.is_handshake_finished())
+            .field("s", &redacted(self.s.is_on()))
+            .field("e", &redacted(self.e.is_on()))
+            .field("rs", &redacted(self.rs.is_on()))
+            .field("re", &redacted(self.re.is_on()))
+            .finish()
     }
 }
@@ -13,7 +13,7 @@
 //! ```
 //! # use snow::Error;
 //! #
-//! # #[cfg(any(feature = "default-resolver", feature = "ring-accelerated"))]
+//! # #[cfg(all(feature = "default-resolver", feature = "std"))]
 //! # fn try_main() -> Result<(), Error> {
 //! static PATTERN: &'static str = "Noise_NN_25519_ChaChaPoly_BLAKE2s";
 //!
@@ -43,7 +43,44 @@
 //! #     Ok(())
 //! # }
 //! #
-//! # #[cfg(not(any(feature = "default-resolver", feature = "ring-accelerated")))]
+//! # #[cfg(not(all(feature = "default-resolver", feature = "std")))]
+//! # fn try_main() -> Result<(), ()> { Ok(()) }
+//! #
+//! # fn main() {
+//! #     try_main().unwrap();
+//! # }
+//! ```
+//!
+//! If you'd rather not manage buffers yourself, the `_vec` variants allocate and return
+//! exactly the bytes written or read:
+//!
+//! ```
+//! # use snow::Error;
+//! #
+//! # #[cfg(all(feature = "default-resolver", feature = "std"))]
+//! # fn try_main() -> Result<(), Error> {
+//! static PATTERN: &'static str = "Noise_NN_25519_ChaChaPoly_BLAKE2s";
+//!
+//! let mut initiator = snow::Builder::new(PATTERN.parse()?).build_initiator()?;
+//! let mut responder = snow::Builder::new(PATTERN.parse()?).build_responder()?;
+//!
+//! // -> e
+//! let first_msg = initiator.write_message_vec(&[])?;
+//! responder.read_message_vec(&first_msg)?;
+//!
+//! // <- e, ee
+//! let second_msg = responder.write_message_vec(b"hello")?;
+//! assert_eq!(initiator.read_message_vec(&second_msg)?, b"hello");
+//!
+//! let mut initiator = initiator.into_transport_mode()?;
+//! let mut responder = responder.into_transport_mode()?;
+//!
+//! let msg = initiator.write_message_vec(b"hack the planet")?;
+//! assert_eq!(responder.read_message_vec(&msg)?, b"hack the planet");
+//! #     Ok(())
+//! # }
+//! #
+//! # #[cfg(not(all(feature = "default-resolver", feature = "std")))]
 //! # fn try_main() -> Result<(), ()> { Ok(()) }
 //! #
 //! # fn main() {
@@ -52,6 +89,9 @@
 //! ```
 
 #![warn(missing_docs)]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
 
 macro_rules! copy_slices {
     ($inslice:expr, $outslice:expr) => {
@@ -68,7 +108,41 @@ macro_rules! static_slice {
 
 macro_rules! bail {
     ($e:expr) => {
-        return Err(($e).into());
+        return Err(($e).into())
+    };
+}
+
+// Forward to `log` with the "logging" feature, and otherwise only type-check the arguments.
+// Never pass them key material or payloads.
+#[cfg(feature = "logging")]
+macro_rules! log_debug {
+    ($($arg:tt)*) => {
+        ::log::debug!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "logging"))]
+macro_rules! log_debug {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = ::core::format_args!($($arg)*);
+        }
+    };
+}
+
+#[cfg(feature = "logging")]
+macro_rules! log_trace {
+    ($($arg:tt)*) => {
+        ::log::trace!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "logging"))]
+macro_rules! log_trace {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = ::core::format_args!($($arg)*);
+        }
     };
 }
 
@@ -77,7 +151,11 @@ mod cipherstate;
 mod constants;
 pub mod error;
 mod handshakestate;
+mod pipe;
+mod split_transportstate;
 mod stateless_transportstate;
+#[cfg(feature = "tokio")]
+mod stream;
 mod symmetricstate;
 mod transportstate;
 mod utils;
@@ -90,6 +168,17 @@ pub use crate::{
     builder::{Builder, Keypair},
     error::Error,
     handshakestate::HandshakeState,
+    params::parse_protocol_name,
+    pipe::NoisePipe,
+    split_transportstate::{TransportReceiver, TransportSender},
     stateless_transportstate::StatelessTransportState,
     transportstate::TransportState,
 };
+
+#[cfg(all(
+    feature = "default-resolver",
+    not(all(feature = "ring-accelerated", feature = "libsodium-accelerated"))
+))]
+pub use crate::builder::supported_protocols;
+#[cfg(feature = "tokio")]
+pub use crate::stream::NoiseStream;
@@ -1,13 +1,21 @@
 //! All structures related to Noise parameter definitions (cryptographic primitive choices, protocol
 //! patterns/names)
 
+use alloc::{
+    borrow::ToOwned,
+    string::{String, ToString},
+};
 use crate::error::{Error, PatternProblem};
-use std::str::FromStr;
+use core::{fmt, str::FromStr};
 mod patterns;
-
-pub use self::patterns::{
-    HandshakeChoice, HandshakeModifier, HandshakeModifierList, HandshakePattern,
-    SUPPORTED_HANDSHAKE_PATTERNS,
+mod policy;
+
+pub use self::{
+    patterns::{
+        HandshakeChoice, HandshakeModifier, HandshakeModifierList, HandshakePattern,
+        SUPPORTED_HANDSHAKE_PATTERNS,
+    },
+    policy::Policy,
 };
 
 pub(crate) use self::patterns::{DhToken, HandshakeTokens, MessagePatterns, Token};
@@ -19,6 +27,15 @@ pub enum BaseChoice {
     Noise,
 }
 
+impl BaseChoice {
+    /// The equivalent of the `ToString` trait, but for `&'static str`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BaseChoice::Noise => "Noise",
+        }
+    }
+}
+
 impl FromStr for BaseChoice {
     type Err = Error;
 
@@ -39,6 +56,26 @@ pub enum DHChoice {
     Ed448,
 }
 
+impl DHChoice {
+    /// The equivalent of the `ToString` trait, but for `&'static str`.
+    pub fn as_str(self) -> &'static str {
+        use self::DHChoice::*;
+        match self {
+            Curve25519 => "25519",
+            Ed448 => "448",
+        }
+    }
+
+    /// The length of public keys and DH outputs, `DHLEN` in the spec.
+    pub fn dh_len(self) -> usize {
+        use self::DHChoice::*;
+        match self {
+            Curve25519 => 32,
+            Ed448 => 56,
+        }
+    }
+}
+
 impl FromStr for DHChoice {
     type Err = Error;
 
@@ -60,6 +97,23 @@ pub enum CipherChoice {
     #[cfg(feature = "xchachapoly")]
     XChaChaPoly,
     AESGCM,
+    #[cfg(feature = "aes128gcm")]
+    AES128GCM,
+}
+
+impl CipherChoice {
+    /// The equivalent of the `ToString` trait, but for `&'static str`.
+    pub fn as_str(self) -> &'static str {
+        use self::CipherChoice::*;
+        match self {
+            ChaChaPoly => "ChaChaPoly",
+            #[cfg(feature = "xchachapoly")]
+            XChaChaPoly => "XChaChaPoly",
+            AESGCM => "AESGCM",
+            #[cfg(feature = "aes128gcm")]
+            AES128GCM => "AES128GCM",
+        }
+    }
 }
 
 impl FromStr for CipherChoice {
@@ -72,6 +126,8 @@ impl FromStr for CipherChoice {
             #[cfg(feature = "xchachapoly")]
             "XChaChaPoly" => Ok(XChaChaPoly),
             "AESGCM" => Ok(AESGCM),
+            #[cfg(feature = "aes128gcm")]
+            "AES128GCM" => Ok(AES128GCM),
             _ => bail!(PatternProblem::UnsupportedCipherType),
         }
     }
@@ -85,6 +141,31 @@ pub enum HashChoice {
     SHA512,
     Blake2s,
     Blake2b,
+    #[cfg(feature = "blake3")]
+    Blake3,
+    #[cfg(feature = "sha3")]
+    SHA3_256,
+    #[cfg(feature = "sha3")]
+    SHA3_512,
+}
+
+impl HashChoice {
+    /// The equivalent of the `ToString` trait, but for `&'static str`.
+    pub fn as_str(self) -> &'static str {
+        use self::HashChoice::*;
+        match self {
+            SHA256 => "SHA256",
+            SHA512 => "SHA512",
+            Blake2s => "BLAKE2s",
+            Blake2b => "BLAKE2b",
+            #[cfg(feature = "blake3")]
+            Blake3 => "BLAKE3",
+            #[cfg(feature = "sha3")]
+            SHA3_256 => "SHA3/256",
+            #[cfg(feature = "sha3")]
+            SHA3_512 => "SHA3/512",
+        }
+    }
 }
 
 impl FromStr for HashChoice {
@@ -97,11 +178,44 @@ impl FromStr for HashChoice {
             "SHA512" => Ok(SHA512),
             "BLAKE2s" => Ok(Blake2s),
             "BLAKE2b" => Ok(Blake2b),
+            #[cfg(feature = "blake3")]
+            "BLAKE3" => Ok(Blake3),
+            #[cfg(feature = "sha3")]
+            "SHA3/256" => Ok(SHA3_256),
+            #[cfg(feature = "sha3")]
+            "SHA3/512" => Ok(SHA3_512),
             _ => bail!(PatternProblem::UnsupportedHashType),
         }
     }
 }
 
+#[doc(hidden)]
+pub const SUPPORTED_DH_CHOICES: &[DHChoice] = &[DHChoice::Curve25519, DHChoice::Ed448];
+
+#[doc(hidden)]
+pub const SUPPORTED_CIPHER_CHOICES: &[CipherChoice] = &[
+    CipherChoice::ChaChaPoly,
+    #[cfg(feature = "xchachapoly")]
+    CipherChoice::XChaChaPoly,
+    CipherChoice::AESGCM,
+    #[cfg(feature = "aes128gcm")]
+    CipherChoice::AES128GCM,
+];
+
+#[doc(hidden)]
+pub const SUPPORTED_HASH_CHOICES: &[HashChoice] = &[
+    HashChoice::SHA256,
+    HashChoice::SHA512,
+    HashChoice::Blake2s,
+    HashChoice::Blake2b,
+    #[cfg(feature = "blake3")]
+    HashChoice::Blake3,
+    #[cfg(feature = "sha3")]
+    HashChoice::SHA3_256,
+    #[cfg(feature = "sha3")]
+    HashChoice::SHA3_512,
+];
+
 /// One of the supported Kems provided for unstable HFS extension.
 #[cfg(feature = "hfs")]
 #[allow(missing_docs)]
@@ -110,6 +224,16 @@ pub enum KemChoice {
     Kyber1024,
 }
 
+#[cfg(feature = "hfs")]
+impl KemChoice {
+    /// The equivalent of the `ToString` trait, but for `&'static str`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            KemChoice::Kyber1024 => "Kyber1024",
+        }
+    }
+}
+
 #[cfg(feature = "hfs")]
 impl FromStr for KemChoice {
     type Err = Error;
@@ -136,6 +260,27 @@ impl FromStr for KemChoice {
 ///
 /// let params: NoiseParams = "Noise_XX_25519_AESGCM_SHA256".parse().unwrap();
 /// ```
+///
+/// `Display` writes the protocol name back out from the individual choices, so it reflects
+/// changes made to them since parsing (unlike `name`):
+///
+/// ```
+/// # use snow::params::*;
+/// let mut params: NoiseParams = "Noise_XXpsk3_25519_AESGCM_SHA256".parse().unwrap();
+/// params.hash = HashChoice::Blake2s;
+/// assert_eq!(params.to_string(), "Noise_XXpsk3_25519_AESGCM_BLAKE2s");
+/// ```
+///
+/// Parse once and clone for each session, as the builder takes ownership:
+///
+/// ```
+/// # use snow::{params::*, Builder};
+/// let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+/// # #[cfg(all(feature = "default-resolver", feature = "std"))]
+/// for _ in 0..3 {
+///     let _responder = Builder::new(params.clone()).build_responder().unwrap();
+/// }
+/// ```
 #[allow(missing_docs)]
 #[derive(PartialEq, Clone, Debug)]
 pub struct NoiseParams {
@@ -176,6 +321,37 @@ impl NoiseParams {
     ) -> Self {
         NoiseParams { name, base, handshake, dh, kem, cipher, hash }
     }
+
+    /// Mix PSKs into the messages at `placements`, as [`HandshakeChoice::place_psks()`] does,
+    /// and update the protocol name to match, since it is hashed into the handshake.
+    pub fn place_psks(&mut self, placements: &[u8]) -> Result<(), Error> {
+        self.handshake.place_psks(placements)?;
+        self.name = self.to_string();
+        Ok(())
+    }
+
+    /// The number of PSKs the handshake needs. See [`HandshakeChoice::psk_positions()`] for
+    /// which ones.
+    pub fn required_psks(&self) -> usize {
+        self.handshake.psk_positions().len()
+    }
+
+    /// The length of public keys and DH outputs for the chosen DH function, e.g. 32 for
+    /// `25519`. Same as [`DHChoice::dh_len()`].
+    pub fn dh_len(&self) -> usize {
+        self.dh.dh_len()
+    }
+}
+
+impl fmt::Display for NoiseParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}_{}", self.base.as_str(), self.handshake, self.dh.as_str())?;
+        #[cfg(feature = "hfs")]
+        if let Some(kem) = self.kem {
+            write!(f, "+{}", kem.as_str())?;
+        }
+        write!(f, "_{}_{}", self.cipher.as_str(), self.hash.as_str())
+    }
 }
 
 impl FromStr for NoiseParams {
@@ -227,10 +403,50 @@ impl FromStr for NoiseParams {
     }
 }
 
+/// The longest protocol name the Noise spec allows.
+const MAX_PROTOCOL_NAME_LEN: usize = 255;
+
+/// Parse a protocol name received from the other party, e.g. in a negotiation message ahead
+/// of the handshake, before building a session with it.
+///
+/// Unlike `str::parse()`, this treats the name as untrusted: it must be at most 255 bytes of
+/// ASCII letters, digits, `_` and `+`, and spelled exactly the way [`NoiseParams`] prints it.
+/// Trailing components and alternative spellings like `psk03` are rejected rather than
+/// ending up in the handshake hash.
+///
+/// # Errors
+///
+/// Will result in `Error::Input` if `name` fails those checks, and in `Error::Pattern` if it
+/// names something unknown or unsupported.
+///
+/// # Examples
+///
+/// ```
+/// let params = snow::parse_protocol_name(b"Noise_XX_25519_ChaChaPoly_BLAKE2s")?;
+/// assert_eq!(params.name, "Noise_XX_25519_ChaChaPoly_BLAKE2s");
+/// assert!(snow::parse_protocol_name(b"Noise_XX_25519_ChaChaPoly_BLAKE2s\0").is_err());
+/// # Ok::<(), snow::Error>(())
+/// ```
+pub fn parse_protocol_name(name: &[u8]) -> Result<NoiseParams, Error> {
+    if name.is_empty()
+        || name.len() > MAX_PROTOCOL_NAME_LEN
+        || !name.iter().all(|&c| c.is_ascii_alphanumeric() || c == b'_' || c == b'+')
+    {
+        bail!(Error::Input);
+    }
+    let name = core::str::from_utf8(name).map_err(|_| Error::Input)?;
+    let params: NoiseParams = name.parse()?;
+    if params.to_string() != name {
+        bail!(Error::Input);
+    }
+    Ok(params)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::convert::TryFrom;
+    use alloc::vec;
+    use core::convert::TryFrom;
 
     #[test]
     fn test_simple_handshake() {
@@ -258,7 +474,42 @@ mod tests {
     #[test]
     fn test_psk_fallback_mod() {
         let p: NoiseParams = "Noise_XXfallback+psk0_25519_AESGCM_SHA256".parse().unwrap();
-        assert!(p.handshake.modifiers.list.len() == 2);
+        assert_eq!(
+            p.handshake.modifiers.list,
+            [HandshakeModifier::Fallback, HandshakeModifier::Psk(0)]
+        );
+
+        // The psk goes into the first message that's still sent after the fallback.
+        let tokens = HandshakeTokens::try_from(&p.handshake).unwrap();
+        assert_eq!(tokens.premsg_pattern_r, &[Token::E]);
+        assert_eq!(tokens.msg_patterns[0][0], Token::Psk(0));
+        assert_eq!(tokens.msg_patterns.len(), 2);
+    }
+
+    #[test]
+    fn test_invalid_modifier_combinations() {
+        for name in [
+            "Noise_XXpsk0+psk0_25519_AESGCM_SHA256",
+            "Noise_XXpsk0+psk2+psk0_25519_AESGCM_SHA256",
+            "Noise_XXfallback+psk3_25519_AESGCM_SHA256",
+        ] {
+            match name.parse::<NoiseParams>() {
+                Err(Error::Pattern(PatternProblem::InvalidPsk)) => {},
+                other => panic!("{} parsed as {:?}", name, other),
+            }
+        }
+
+        for name in [
+            "Noise_XXfallback+fallback_25519_AESGCM_SHA256",
+            "Noise_XXpsk0+fallback_25519_AESGCM_SHA256",
+            "Noise_XXfallback+psk0+bogus_25519_AESGCM_SHA256",
+            "Noise_XXfallback++psk0_25519_AESGCM_SHA256",
+        ] {
+            match name.parse::<NoiseParams>() {
+                Err(Error::Pattern(PatternProblem::UnsupportedModifier)) => {},
+                other => panic!("{} parsed as {:?}", name, other),
+            }
+        }
     }
 
     #[test]
@@ -309,4 +560,181 @@ mod tests {
             _ => panic!("missing token!"),
         }
     }
+
+    #[test]
+    fn test_modified_psk_in_last_message() {
+        let p: NoiseParams = "Noise_XXpsk1+psk3_25519_AESGCM_SHA256".parse().unwrap();
+        let tokens = HandshakeTokens::try_from(&p.handshake).unwrap();
+
+        assert_eq!(tokens.msg_patterns[0].last(), Some(&Token::Psk(1)));
+        assert!(!tokens.msg_patterns[1].iter().any(|t| matches!(t, Token::Psk(_))));
+        assert_eq!(tokens.msg_patterns[2].last(), Some(&Token::Psk(3)));
+    }
+
+    #[test]
+    fn test_required_psks() {
+        for (name, positions) in [
+            ("Noise_XX_25519_AESGCM_SHA256", &[][..]),
+            ("Noise_XXpsk3_25519_AESGCM_SHA256", &[3][..]),
+            ("Noise_NNpsk0+psk2_25519_AESGCM_SHA256", &[0, 2][..]),
+            ("Noise_XXfallback+psk0_25519_AESGCM_SHA256", &[0][..]),
+            ("Noise_KKpsk2+psk0+psk1_25519_AESGCM_SHA256", &[0, 1, 2][..]),
+        ] {
+            let p: NoiseParams = name.parse().unwrap();
+            assert_eq!(p.handshake.psk_positions(), positions, "{}", name);
+            assert_eq!(p.required_psks(), positions.len(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_place_psks() {
+        let mut p: NoiseParams = "Noise_XXfallback_25519_AESGCM_SHA256".parse().unwrap();
+        p.place_psks(&[0]).unwrap();
+        assert_eq!(p.name, "Noise_XXfallback+psk0_25519_AESGCM_SHA256");
+        assert_eq!(p, p.name.parse().unwrap());
+
+        // Positions from the protocol name win.
+        let mut p: NoiseParams = "Noise_NNpsk2_25519_AESGCM_SHA256".parse().unwrap();
+        p.place_psks(&[0, 1]).unwrap();
+        assert_eq!(p.handshake.psk_positions(), [2]);
+
+        for placements in [&[0, 0][..], &[3][..]] {
+            let mut p: NoiseParams = "Noise_NN_25519_AESGCM_SHA256".parse().unwrap();
+            assert!(matches!(
+                p.place_psks(placements),
+                Err(Error::Pattern(PatternProblem::InvalidPsk))
+            ));
+            assert_eq!(p.name, "Noise_NN_25519_AESGCM_SHA256");
+            assert!(!p.handshake.is_psk());
+        }
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        for name in [
+            "Noise_NN_25519_ChaChaPoly_SHA256",
+            "Noise_XX_448_AESGCM_BLAKE2b",
+            "Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s",
+            "Noise_XXpsk0+psk3_25519_AESGCM_SHA512",
+            "Noise_XXfallback+psk0_25519_AESGCM_SHA256",
+            "Noise_X1K1_448_ChaChaPoly_SHA512",
+            "Noise_Npsk0_25519_ChaChaPoly_BLAKE2s",
+        ] {
+            let p: NoiseParams = name.parse().unwrap();
+            assert_eq!(p.to_string(), name);
+            assert_eq!(p.to_string().parse::<NoiseParams>().unwrap(), p);
+        }
+    }
+
+    #[cfg(feature = "hfs")]
+    #[test]
+    fn test_display_round_trip_hfs() {
+        let name = "Noise_XXhfs_25519+Kyber1024_ChaChaPoly_BLAKE2s";
+        let p: NoiseParams = name.parse().unwrap();
+        assert_eq!(p.to_string(), name);
+    }
+
+    #[test]
+    fn test_fundamental_pattern_key_requirements() {
+        use HandshakePattern::*;
+
+        // (pattern, initiator static, responder static, initiator knows rs, responder knows rs)
+        for &(pattern, i_s, r_s, i_rs, r_rs) in &[
+            (NN, false, false, false, false),
+            (NK, false, true, true, false),
+            (NX, false, true, false, false),
+            (XN, true, false, false, false),
+            (XK, true, true, true, false),
+            (XX, true, true, false, false),
+            (KN, true, false, false, true),
+            (KK, true, true, true, true),
+            (KX, true, true, false, true),
+            (IN, true, false, false, false),
+            (IK, true, true, true, false),
+            (IX, true, true, false, false),
+        ] {
+            assert_eq!(pattern.needs_local_static_key(true), i_s, "{:?}", pattern);
+            assert_eq!(pattern.needs_local_static_key(false), r_s, "{:?}", pattern);
+            assert_eq!(pattern.need_known_remote_pubkey(true), i_rs, "{:?}", pattern);
+            assert_eq!(pattern.need_known_remote_pubkey(false), r_rs, "{:?}", pattern);
+        }
+    }
+
+    #[test]
+    fn test_dh_len() {
+        let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+        assert_eq!(params.dh_len(), 32);
+        let params: NoiseParams = "Noise_XX_448_ChaChaPoly_BLAKE2s".parse().unwrap();
+        assert_eq!(params.dh_len(), 56);
+    }
+
+    #[test]
+    fn test_invalid_psk_mod() {
+        for name in [
+            "Noise_XXpsk4_25519_AESGCM_SHA256",
+            "Noise_NNpsk0+psk3_25519_AESGCM_SHA256",
+            "Noise_Npsk2_25519_AESGCM_SHA256",
+            "Noise_XXpsk256_25519_AESGCM_SHA256",
+            "Noise_XXpsk_25519_AESGCM_SHA256",
+            "Noise_XXpskx_25519_AESGCM_SHA256",
+        ] {
+            match name.parse::<NoiseParams>() {
+                Err(Error::Pattern(PatternProblem::InvalidPsk)) => {},
+                other => panic!("{} parsed as {:?}", name, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_protocol_name() {
+        #[allow(unused_mut)]
+        let mut valid = vec![
+            "Noise_XX_25519_ChaChaPoly_BLAKE2s",
+            "Noise_IKpsk2_448_AESGCM_SHA512",
+            "Noise_XXfallback+psk0_25519_AESGCM_SHA256",
+        ];
+        #[cfg(feature = "hfs")]
+        valid.push("Noise_XXhfs_25519+Kyber1024_ChaChaPoly_SHA256");
+        for name in valid {
+            let params = parse_protocol_name(name.as_bytes()).unwrap();
+            assert_eq!(params.name, name);
+        }
+
+        let long = ["Noise_XX_25519_ChaChaPoly_BLAKE2s"; 8].join("");
+        for name in [
+            &b""[..],
+            long.as_bytes(),
+            b"Noise_XX_25519_ChaChaPoly_BLAKE2s\0",
+            b"Noise_XX_25519_ChaChaPoly_BLAKE2s\n",
+            b" Noise_XX_25519_ChaChaPoly_BLAKE2s",
+            b"Noise_XX_25519_ChaChaPoly_BLAKE2s_junk",
+            b"Noise_XXpsk03_25519_ChaChaPoly_BLAKE2s",
+            b"Noise_XX_25519_Cha\xc3\xa7haPoly_BLAKE2s",
+            b"Noise_XX_25519_\xff\xfe_BLAKE2s",
+        ] {
+            match parse_protocol_name(name) {
+                Err(Error::Input) => {},
+                other => panic!("{:?} parsed as {:?}", name, other),
+            }
+        }
+
+        for name in [
+            "Noise",
+            "Noise_XX_25519_ChaChaPoly",
+            "Noise__25519_ChaChaPoly_BLAKE2s",
+            "Noise_XX+_25519_ChaChaPoly_BLAKE2s",
+            "Noise_XXpsk+psk_25519_ChaChaPoly_BLAKE2s",
+            "Noise_XXpsk99999999999_25519_ChaChaPoly_BLAKE2s",
+            "Noise_XX_25519+_ChaChaPoly_BLAKE2s",
+            "Noise_ZZ_25519_ChaChaPoly_BLAKE2s",
+            "Noisy_XX_25519_ChaChaPoly_BLAKE2s",
+            "Noise_XX_25519_ChaChaPoly_MD5",
+            "Noise_XX_25519_ChaChaPoly_BLAKE2s+",
+        ] {
+            match parse_protocol_name(name.as_bytes()) {
+                Err(Error::Pattern(_)) => {},
+                other => panic!("{} parsed as {:?}", name, other),
+            }
+        }
+    }
 }
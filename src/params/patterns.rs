@@ -1,5 +1,6 @@
+use alloc::{vec, vec::Vec};
 use crate::error::{Error, PatternProblem};
-use std::{convert::TryFrom, str::FromStr};
+use core::{convert::TryFrom, fmt, str::FromStr};
 
 /// A small helper macro that behaves similar to the `vec![]` standard macro,
 /// except it allocates a bit extra to avoid resizing.
@@ -175,7 +176,7 @@ impl FromStr for HandshakeModifier {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             s if s.starts_with("psk") => Ok(HandshakeModifier::Psk(
-                (&s[3..]).parse().map_err(|_| PatternProblem::InvalidPsk)?,
+                s[3..].parse().map_err(|_| PatternProblem::InvalidPsk)?,
             )),
             "fallback" => Ok(HandshakeModifier::Fallback),
             #[cfg(feature = "hfs")]
@@ -185,7 +186,21 @@ impl FromStr for HandshakeModifier {
     }
 }
 
+impl fmt::Display for HandshakeModifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeModifier::Psk(n) => write!(f, "psk{}", n),
+            HandshakeModifier::Fallback => f.write_str("fallback"),
+            #[cfg(feature = "hfs")]
+            HandshakeModifier::Hfs => f.write_str("hfs"),
+        }
+    }
+}
+
 /// Handshake modifiers that will be used during key exchange handshake.
+///
+/// Parsed from the modifiers joined with `+` after the base pattern, as in `fallback+psk0`,
+/// and applied in that order. Each modifier may only appear once.
 #[derive(Clone, PartialEq, Debug)]
 pub struct HandshakeModifierList {
     /// List of parsed modifiers.
@@ -202,15 +217,57 @@ impl FromStr for HandshakeModifierList {
             let modifier_names = s.split('+');
             let mut modifiers = vec![];
             for modifier_name in modifier_names {
-                modifiers.push(modifier_name.parse()?);
+                let modifier: HandshakeModifier = modifier_name.parse()?;
+                if modifiers.contains(&modifier) {
+                    match modifier {
+                        HandshakeModifier::Psk(_) => bail!(PatternProblem::InvalidPsk),
+                        _ => bail!(PatternProblem::UnsupportedModifier),
+                    }
+                }
+                modifiers.push(modifier);
             }
             Ok(HandshakeModifierList { list: modifiers })
         }
     }
 }
 
+impl fmt::Display for HandshakeModifierList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, modifier) in self.list.iter().enumerate() {
+            if i > 0 {
+                f.write_str("+")?;
+            }
+            write!(f, "{}", modifier)?;
+        }
+        Ok(())
+    }
+}
+
 /// The pattern/modifier combination choice (no primitives specified)
 /// for a full noise protocol definition.
+///
+/// PSK positions come from the `pskN` modifiers, so they can also be chosen in code with
+/// [`NoiseParams::place_psks()`] or [`Builder::psk_placements()`]:
+///
+/// ```
+/// # use snow::{params::*, Builder};
+/// let mut params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+/// params.place_psks(&[3]).unwrap();
+/// assert_eq!(params.name, "Noise_XXpsk3_25519_ChaChaPoly_BLAKE2s");
+///
+/// # #[cfg(all(feature = "default-resolver", feature = "std"))]
+/// # {
+/// let key = Builder::new(params.clone()).generate_keypair().unwrap();
+/// let _initiator = Builder::new(params)
+///     .local_private_key(&key.private)
+///     .psk(3, &[7u8; 32])
+///     .build_initiator()
+///     .unwrap();
+/// # }
+/// ```
+///
+/// [`NoiseParams::place_psks()`]: crate::params::NoiseParams::place_psks
+/// [`Builder::psk_placements()`]: crate::Builder::psk_placements
 #[derive(Clone, PartialEq, Debug)]
 pub struct HandshakeChoice {
     /// The base pattern itself
@@ -231,6 +288,52 @@ impl HandshakeChoice {
         false
     }
 
+    /// The positions of the PSKs the handshake needs, i.e. the `N` of each `pskN` modifier,
+    /// in ascending order. Each must be given to [`Builder::psk()`], or to
+    /// [`HandshakeState::set_psk()`] before its token is processed.
+    ///
+    /// [`Builder::psk()`]: crate::Builder::psk
+    /// [`HandshakeState::set_psk()`]: crate::HandshakeState::set_psk
+    pub fn psk_positions(&self) -> Vec<u8> {
+        let mut positions: Vec<u8> = self
+            .modifiers
+            .list
+            .iter()
+            .filter_map(|modifier| match *modifier {
+                HandshakeModifier::Psk(n) => Some(n),
+                _ => None,
+            })
+            .collect();
+        positions.sort_unstable();
+        positions.dedup();
+        positions
+    }
+
+    /// Add a `pskN` modifier for each of `placements`, the indices of the messages to mix a
+    /// PSK into, in ascending order. A choice that already has `pskN` modifiers is left as it
+    /// is, since those are what the protocol name declares.
+    ///
+    /// Fails with `PatternProblem::InvalidPsk` if a placement is repeated or doesn't fit the
+    /// pattern, leaving the choice unchanged.
+    pub fn place_psks(&mut self, placements: &[u8]) -> Result<(), Error> {
+        if self.is_psk() {
+            return Ok(());
+        }
+        let mut placements = placements.to_vec();
+        placements.sort_unstable();
+        let mut choice = self.clone();
+        for n in placements {
+            let modifier = HandshakeModifier::Psk(n);
+            if choice.modifiers.list.contains(&modifier) {
+                bail!(PatternProblem::InvalidPsk);
+            }
+            choice.modifiers.list.push(modifier);
+        }
+        HandshakeTokens::try_from(&choice)?;
+        *self = choice;
+        Ok(())
+    }
+
     /// Whether the handshake choice includes the fallback modifier.
     pub fn is_fallback(&self) -> bool {
         self.modifiers.list.contains(&HandshakeModifier::Fallback)
@@ -246,7 +349,7 @@ impl HandshakeChoice {
     fn parse_pattern_and_modifier(s: &str) -> Result<(HandshakePattern, &str), Error> {
         for i in (1..=4).rev() {
             if s.len() > i - 1 && s.is_char_boundary(i) {
-                if let Ok(p) = (&s[..i]).parse() {
+                if let Ok(p) = s[..i].parse() {
                     return Ok((p, &s[i..]));
                 }
             }
@@ -262,8 +365,17 @@ impl FromStr for HandshakeChoice {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let (pattern, remainder) = Self::parse_pattern_and_modifier(s)?;
         let modifiers = remainder.parse()?;
+        let choice = HandshakeChoice { pattern, modifiers };
 
-        Ok(HandshakeChoice { pattern, modifiers })
+        // Catch modifiers that can't apply to this pattern, like a psk past its last message.
+        HandshakeTokens::try_from(&choice)?;
+        Ok(choice)
+    }
+}
+
+impl fmt::Display for HandshakeChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.pattern.as_str(), self.modifiers)
     }
 }
 
@@ -488,10 +600,10 @@ impl<'a> TryFrom<&'a HandshakeChoice> for HandshakeTokens {
 
         for modifier in handshake.modifiers.list.iter() {
             match modifier {
-                HandshakeModifier::Psk(n) => apply_psk_modifier(&mut patterns, *n),
+                HandshakeModifier::Psk(n) => apply_psk_modifier(&mut patterns, *n)?,
+                HandshakeModifier::Fallback => apply_fallback_modifier(&mut patterns)?,
                 #[cfg(feature = "hfs")]
                 HandshakeModifier::Hfs => apply_hfs_modifier(&mut patterns),
-                _ => bail!(PatternProblem::UnsupportedModifier),
             }
         }
 
@@ -521,7 +633,11 @@ fn check_hfs_and_oneway_conflict(_: &HandshakeChoice) -> Result<(), Error> {
     Ok(())
 }
 
-fn apply_psk_modifier(patterns: &mut Patterns, n: u8) {
+fn apply_psk_modifier(patterns: &mut Patterns, n: u8) -> Result<(), Error> {
+    // pskN appends the psk token to message N, so it can't be past the last message.
+    if n as usize > patterns.2.len() {
+        bail!(PatternProblem::InvalidPsk);
+    }
     match n {
         0 => {
             patterns.2[0].insert(0, Token::Psk(n));
@@ -531,6 +647,37 @@ fn apply_psk_modifier(patterns: &mut Patterns, n: u8) {
             patterns.2[i].push(Token::Psk(n));
         },
     }
+    Ok(())
+}
+
+/// Convert an Alice-initiated pattern into its Bob-initiated fallback form.
+///
+/// Alice's first message becomes a pre-message, and Bob (the former responder) becomes the
+/// initiator of the remaining messages, so the asymmetric DH tokens swap sides.
+///
+/// See: http://noiseprotocol.org/noise.html#the-fallback-modifier
+fn apply_fallback_modifier(patterns: &mut Patterns) -> Result<(), Error> {
+    if !patterns.0.is_empty() || patterns.2.len() < 2 {
+        bail!(PatternProblem::UnsupportedModifier);
+    }
+
+    let premsg: PremessagePatterns = match patterns.2.remove(0)[..] {
+        [E] => static_slice![Token: E],
+        [S] => static_slice![Token: S],
+        [E, S] => static_slice![Token: E, S],
+        _ => bail!(PatternProblem::UnsupportedModifier),
+    };
+    patterns.0 = patterns.1;
+    patterns.1 = premsg;
+
+    for token in patterns.2.iter_mut().flat_map(|msg| msg.iter_mut()) {
+        *token = match *token {
+            Dh(Es) => Dh(Se),
+            Dh(Se) => Dh(Es),
+            t => t,
+        };
+    }
+    Ok(())
 }
 
 #[cfg(feature = "hfs")]
@@ -0,0 +1,101 @@
+use super::{CipherChoice, DHChoice, HandshakeModifier, HandshakePattern, HashChoice, NoiseParams};
+use alloc::vec::Vec;
+use crate::error::{Error, PatternProblem, PolicyComponent};
+
+/// An allowlist of the parameters an application is willing to use, to guard against being
+/// talked into weaker ones. Pass it to [`Builder::require()`], or [`check()`] parsed params
+/// directly.
+///
+/// Every kind of component is unrestricted until an allowlist is given for it.
+///
+/// # Examples
+///
+/// ```
+/// # use snow::params::*;
+/// let policy = Policy::new()
+///     .patterns(&[HandshakePattern::XX, HandshakePattern::IK])
+///     .dh(&[DHChoice::Curve25519])
+///     .ciphers(&[CipherChoice::ChaChaPoly])
+///     .hashes(&[HashChoice::Blake2s]);
+///
+/// assert!(policy.check(&"Noise_IK_25519_ChaChaPoly_BLAKE2s".parse().unwrap()).is_ok());
+/// assert!(policy.check(&"Noise_XX_25519_AESGCM_BLAKE2s".parse().unwrap()).is_err());
+/// ```
+///
+/// [`Builder::require()`]: crate::Builder::require
+/// [`check()`]: #method.check
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    patterns:  Option<Vec<HandshakePattern>>,
+    modifiers: Option<Vec<HandshakeModifier>>,
+    dh:        Option<Vec<DHChoice>>,
+    ciphers:   Option<Vec<CipherChoice>>,
+    hashes:    Option<Vec<HashChoice>>,
+}
+
+impl Policy {
+    /// Create a policy that allows everything.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only allow these handshake patterns.
+    pub fn patterns(mut self, allowed: &[HandshakePattern]) -> Self {
+        self.patterns = Some(allowed.to_vec());
+        self
+    }
+
+    /// Only allow these handshake modifiers. Pass an empty list to allow none.
+    pub fn modifiers(mut self, allowed: &[HandshakeModifier]) -> Self {
+        self.modifiers = Some(allowed.to_vec());
+        self
+    }
+
+    /// Only allow these DH functions.
+    pub fn dh(mut self, allowed: &[DHChoice]) -> Self {
+        self.dh = Some(allowed.to_vec());
+        self
+    }
+
+    /// Only allow these cipher functions.
+    pub fn ciphers(mut self, allowed: &[CipherChoice]) -> Self {
+        self.ciphers = Some(allowed.to_vec());
+        self
+    }
+
+    /// Only allow these hash functions.
+    pub fn hashes(mut self, allowed: &[HashChoice]) -> Self {
+        self.hashes = Some(allowed.to_vec());
+        self
+    }
+
+    /// Check `params` against the policy.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `PatternProblem::PolicyViolation` naming the first component that isn't
+    /// allowed.
+    pub fn check(&self, params: &NoiseParams) -> Result<(), Error> {
+        let violation = if !allows(&self.patterns, &params.handshake.pattern) {
+            PolicyComponent::Pattern
+        } else if !params.handshake.modifiers.list.iter().all(|m| allows(&self.modifiers, m)) {
+            PolicyComponent::Modifier
+        } else if !allows(&self.dh, &params.dh) {
+            PolicyComponent::Dh
+        } else if !allows(&self.ciphers, &params.cipher) {
+            PolicyComponent::Cipher
+        } else if !allows(&self.hashes, &params.hash) {
+            PolicyComponent::Hash
+        } else {
+            return Ok(());
+        };
+        bail!(PatternProblem::PolicyViolation(violation))
+    }
+}
+
+fn allows<T: PartialEq>(allowed: &Option<Vec<T>>, choice: &T) -> bool {
+    match allowed {
+        Some(allowed) => allowed.contains(choice),
+        None => true,
+    }
+}
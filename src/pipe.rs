@@ -0,0 +1,268 @@
+use alloc::{string::ToString, vec, vec::Vec};
+use crate::{
+    builder::Builder,
+    error::{Error, PatternProblem},
+    handshakestate::HandshakeState,
+    params::{
+        HandshakeChoice, HandshakeModifier, HandshakeModifierList, HandshakePattern, NoiseParams,
+    },
+    transportstate::TransportState,
+};
+use core::fmt;
+
+/// A [Noise Pipes](http://noiseprotocol.org/noise.html#noise-pipes) handshake: `IK` when the
+/// initiator has the responder's static key cached, switching to `XXfallback` when it doesn't
+/// or the cached key turns out to be stale.
+///
+/// Both sides are built from a [`Builder`] set up for `IK` (e.g.
+/// `Noise_IK_25519_ChaChaPoly_BLAKE2s`) with their local private key. The initiator adds the
+/// responder's static key with [`Builder::remote_public_key()`] if it has one. Then messages
+/// are passed back and forth like with a [`HandshakeState`], until the handshake is finished:
+/// after two messages if `IK` worked, or three if the pipe fell back.
+///
+/// The responder tells the two apart by trying to read the first message as `IK`, and the
+/// initiator by trying to read the second message as `IK` before `XXfallback`, so no extra
+/// framing is needed. The payload of the first message is only delivered if `IK` works: the
+/// responder's [`read_message()`] returns 0 bytes when it falls back instead. Unlike with a
+/// `HandshakeState`, a failed read can't be retried.
+///
+/// # Examples
+///
+/// ```
+/// # use snow::{Builder, NoisePipe};
+/// #
+/// # #[cfg(all(feature = "default-resolver", feature = "std"))]
+/// # fn try_main() -> Result<(), snow::Error> {
+/// # let params: snow::params::NoiseParams = "Noise_IK_25519_ChaChaPoly_BLAKE2s".parse()?;
+/// let responder_keys = Builder::new(params.clone()).generate_keypair()?;
+/// let initiator_keys = Builder::new(params.clone()).generate_keypair()?;
+///
+/// // The initiator doesn't know the responder's static key yet.
+/// let builder = Builder::new(params.clone()).local_private_key(&initiator_keys.private);
+/// let mut initiator = NoisePipe::initiate(builder)?;
+/// let builder = Builder::new(params).local_private_key(&responder_keys.private);
+/// let mut responder = NoisePipe::respond(builder)?;
+///
+/// let (mut message, mut payload) = ([0u8; 1024], [0u8; 1024]);
+/// let (mut writer, mut reader) = (&mut initiator, &mut responder);
+/// while !writer.is_handshake_finished() {
+///     let len = writer.write_message(&[], &mut message)?;
+///     reader.read_message(&message[..len], &mut payload)?;
+///     std::mem::swap(&mut writer, &mut reader);
+/// }
+///
+/// assert!(initiator.is_fallback());
+/// assert_eq!(initiator.get_remote_static(), Some(&responder_keys.public[..]));
+/// let transport = initiator.into_transport_mode()?;
+/// #     Ok(())
+/// # }
+/// #
+/// # #[cfg(not(all(feature = "default-resolver", feature = "std")))]
+/// # fn try_main() -> Result<(), ()> { Ok(()) }
+/// #
+/// # fn main() {
+/// #     try_main().unwrap();
+/// # }
+/// ```
+///
+/// [`Builder::remote_public_key()`]: crate::Builder::remote_public_key
+/// [`read_message()`]: #method.read_message
+pub struct NoisePipe {
+    state:    HandshakeState,
+    fallback: NoiseParams,
+    // The one owned copy of the prologue: `XXfallback` hashes it again after the builder that
+    // borrowed it is gone.
+    prologue: Vec<u8>,
+    settled:  bool,
+}
+
+impl NoisePipe {
+    /// Start a pipe as the initiator.
+    ///
+    /// If `builder` has the responder's static key, the first message attempts `IK`.
+    /// Otherwise it only carries an ephemeral key, and the pipe goes straight to `XXfallback`.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Pattern` if `builder` isn't set up for `IK` without modifiers,
+    /// and otherwise fails like [`Builder::build_initiator()`].
+    ///
+    /// [`Builder::build_initiator()`]: crate::Builder::build_initiator
+    pub fn initiate(mut builder: Builder<'_>) -> Result<Self, Error> {
+        let fallback = fallback_params(builder.get_params())?;
+        let prologue = builder.take_prologue()?;
+        let state = if builder.has_remote_public_key() {
+            builder.build_initiator()?
+        } else {
+            let xx = with_handshake(builder.get_params(), HandshakePattern::XX, vec![]);
+            builder.with_params(xx).build_initiator()?
+        };
+        Ok(NoisePipe { state, fallback, prologue, settled: false })
+    }
+
+    /// Start a pipe as the responder.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Pattern` if `builder` isn't set up for `IK` without modifiers,
+    /// and otherwise fails like [`Builder::build_responder()`].
+    ///
+    /// [`Builder::build_responder()`]: crate::Builder::build_responder
+    pub fn respond(mut builder: Builder<'_>) -> Result<Self, Error> {
+        let fallback = fallback_params(builder.get_params())?;
+        let prologue = builder.take_prologue()?;
+        let state = builder.build_responder()?;
+        Ok(NoisePipe { state, fallback, prologue, settled: false })
+    }
+
+    /// Write the next handshake message, like [`HandshakeState::write_message()`].
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`HandshakeState::write_message()`].
+    ///
+    /// [`HandshakeState::write_message()`]: crate::HandshakeState::write_message
+    pub fn write_message(&mut self, payload: &[u8], message: &mut [u8]) -> Result<usize, Error> {
+        let len = self.state.write_message(payload, message)?;
+        if !self.settled && self.state.get_params().handshake.pattern == HandshakePattern::XX {
+            // Without the responder's static key, there's no `IK` to attempt.
+            self.fall_back()?;
+        }
+        Ok(len)
+    }
+
+    /// Read the next handshake message, like [`HandshakeState::read_message()`], switching
+    /// to `XXfallback` if the message says so.
+    ///
+    /// Returns the size of the payload written to `payload`. That is 0 for the first message
+    /// if the responder falls back, as its payload can't be decrypted.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`HandshakeState::read_message()`]. The pipe can't be used further after
+    /// an error.
+    ///
+    /// [`HandshakeState::read_message()`]: crate::HandshakeState::read_message
+    pub fn read_message(&mut self, message: &[u8], payload: &mut [u8]) -> Result<usize, Error> {
+        if self.settled || self.state.is_my_turn() {
+            return self.state.read_message(message, payload);
+        }
+
+        if let Ok(len) = self.state.read_message(message, payload) {
+            self.settled = true;
+            return Ok(len);
+        }
+        let responder = !self.state.is_initiator();
+        self.fall_back()?;
+        if responder {
+            Ok(0)
+        } else {
+            // The responder couldn't read our `IK` message and sent the first `XXfallback` one.
+            self.state.read_message(message, payload)
+        }
+    }
+
+    /// Check whether the pipe switched to `XXfallback`. Note that this swaps the roles: the
+    /// responder becomes the initiator of the fallback handshake.
+    pub fn is_fallback(&self) -> bool {
+        self.state.get_params().handshake.is_fallback()
+    }
+
+    /// Check whether it is our turn to send.
+    pub fn is_my_turn(&self) -> bool {
+        self.state.is_my_turn()
+    }
+
+    /// Check whether it is our turn to send and the handshake isn't finished yet, like
+    /// [`HandshakeState::is_my_turn_to_write()`].
+    pub fn is_my_turn_to_write(&self) -> bool {
+        self.state.is_my_turn_to_write()
+    }
+
+    /// Check whether the handshake is finished, and [`into_transport_mode()`] can be called.
+    ///
+    /// [`into_transport_mode()`]: #method.into_transport_mode
+    pub fn is_handshake_finished(&self) -> bool {
+        self.state.is_handshake_finished()
+    }
+
+    /// Get the remote party's static public key, if available.
+    pub fn get_remote_static(&self) -> Option<&[u8]> {
+        self.state.get_remote_static()
+    }
+
+    /// Get the handshake hash of whichever handshake is in progress.
+    pub fn get_handshake_hash(&self) -> &[u8] {
+        self.state.get_handshake_hash()
+    }
+
+    /// Check whether the payload of the last message read was encrypted, like
+    /// [`HandshakeState::was_read_payload_encrypted()`].
+    pub fn was_read_payload_encrypted(&self) -> bool {
+        self.state.was_read_payload_encrypted()
+    }
+
+    /// Check whether the payload of the last message read had forward secrecy, like
+    /// [`HandshakeState::was_read_payload_forward_secret()`].
+    pub fn was_read_payload_forward_secret(&self) -> bool {
+        self.state.was_read_payload_forward_secret()
+    }
+
+    /// Check whether the payload of the last message read could have been replayed, like
+    /// [`HandshakeState::was_read_payload_replayable()`]. That's the case for the first
+    /// message when `IK` works.
+    pub fn was_read_payload_replayable(&self) -> bool {
+        self.state.was_read_payload_replayable()
+    }
+
+    /// Check whether the remote party has proven it holds its static private key, like
+    /// [`HandshakeState::is_remote_authenticated()`].
+    pub fn is_remote_authenticated(&self) -> bool {
+        self.state.is_remote_authenticated()
+    }
+
+    /// Convert the finished handshake into a `TransportState`.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::State` if the handshake isn't finished.
+    pub fn into_transport_mode(self) -> Result<TransportState, Error> {
+        self.state.into_transport_mode()
+    }
+
+    fn fall_back(&mut self) -> Result<(), Error> {
+        self.settled = true;
+        self.state.fall_back(self.fallback.clone(), &self.prologue)
+    }
+}
+
+impl fmt::Debug for NoisePipe {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("NoisePipe")
+            .field("state", &self.state)
+            .field("settled", &self.settled)
+            .finish()
+    }
+}
+
+/// The `XXfallback` params to go with the `IK` ones in `params`.
+fn fallback_params(params: &NoiseParams) -> Result<NoiseParams, Error> {
+    if params.handshake.pattern != HandshakePattern::IK
+        || !params.handshake.modifiers.list.is_empty()
+    {
+        bail!(PatternProblem::UnsupportedHandshakeType);
+    }
+    Ok(with_handshake(params, HandshakePattern::XX, vec![HandshakeModifier::Fallback]))
+}
+
+fn with_handshake(
+    params: &NoiseParams,
+    pattern: HandshakePattern,
+    modifiers: Vec<HandshakeModifier>,
+) -> NoiseParams {
+    let mut params = params.clone();
+    params.handshake =
+        HandshakeChoice { pattern, modifiers: HandshakeModifierList { list: modifiers } };
+    params.name = params.to_string();
+    params
+}
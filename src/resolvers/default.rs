@@ -6,15 +6,22 @@ use chacha20poly1305::{
     ChaCha20Poly1305,
 };
 use core::convert::TryInto;
+#[cfg(feature = "elligator2")]
+use curve25519_elligator2::{MapToPointVariant, MontgomeryPoint, Randomized};
 #[cfg(feature = "pqclean_kyber1024")]
 use pqcrypto_kyber::kyber1024;
 #[cfg(feature = "pqclean_kyber1024")]
 use pqcrypto_traits::kem::{Ciphertext, PublicKey, SecretKey, SharedSecret};
+#[cfg(feature = "std")]
 use rand::rngs::OsRng;
 use sha2::{Digest, Sha256, Sha512};
+#[cfg(feature = "sha3")]
+use sha3::{Sha3_256, Sha3_512};
 use x25519_dalek as x25519;
+use zeroize::Zeroize;
 
 use super::CryptoResolver;
+use alloc::boxed::Box;
 #[cfg(feature = "pqclean_kyber1024")]
 use crate::params::KemChoice;
 #[cfg(feature = "pqclean_kyber1024")]
@@ -28,18 +35,29 @@ use crate::{
 /// The default resolver provided by snow. This resolver is designed to
 /// support as many of the Noise spec primitives as possible with
 /// pure-Rust (or nearly pure-Rust) implementations.
+///
+/// Without the `std` feature, no RNG or Curve448 implementation is provided.
 #[derive(Default)]
 pub struct DefaultResolver;
 
 impl CryptoResolver for DefaultResolver {
+    #[cfg(feature = "std")]
     fn resolve_rng(&self) -> Option<Box<dyn Random>> {
-        Some(Box::new(OsRng::default()))
+        Some(Box::new(OsRng))
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn resolve_rng(&self) -> Option<Box<dyn Random>> {
+        None
     }
 
     fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<dyn Dh>> {
         match *choice {
             DHChoice::Curve25519 => Some(Box::new(Dh25519::default())),
-            _ => None,
+            #[cfg(feature = "std")]
+            DHChoice::Ed448 => Some(Box::new(Dh448::default())),
+            #[cfg(not(feature = "std"))]
+            DHChoice::Ed448 => None,
         }
     }
 
@@ -49,6 +67,12 @@ impl CryptoResolver for DefaultResolver {
             HashChoice::SHA512 => Some(Box::new(HashSHA512::default())),
             HashChoice::Blake2s => Some(Box::new(HashBLAKE2s::default())),
             HashChoice::Blake2b => Some(Box::new(HashBLAKE2b::default())),
+            #[cfg(feature = "blake3")]
+            HashChoice::Blake3 => Some(Box::new(HashBLAKE3::default())),
+            #[cfg(feature = "sha3")]
+            HashChoice::SHA3_256 => Some(Box::new(HashSHA3_256::default())),
+            #[cfg(feature = "sha3")]
+            HashChoice::SHA3_512 => Some(Box::new(HashSHA3_512::default())),
         }
     }
 
@@ -58,6 +82,8 @@ impl CryptoResolver for DefaultResolver {
             #[cfg(feature = "xchachapoly")]
             CipherChoice::XChaChaPoly => Some(Box::new(CipherXChaChaPoly::default())),
             CipherChoice::AESGCM => Some(Box::new(CipherAesGcm::default())),
+            #[cfg(feature = "aes128gcm")]
+            CipherChoice::AES128GCM => Some(Box::new(CipherAes128Gcm::default())),
         }
     }
 
@@ -76,12 +102,32 @@ struct Dh25519 {
     pubkey:  [u8; 32],
 }
 
+/// Wraps x448.
+#[cfg(feature = "std")]
+struct Dh448 {
+    privkey: [u8; 56],
+    pubkey:  [u8; 56],
+}
+
 /// Wraps `aes-gcm`'s AES256-GCM implementation.
+///
+/// There's no separate hardware path here: `aes` and `polyval` detect AES-NI and CLMUL (or
+/// the ARMv8 equivalents, with their `armv8` feature) at runtime, and fall back to constant
+/// time software implementations without them. The software path can be forced with the
+/// `aes-gcm/force-soft` feature, which CI runs the AES-GCM tests with.
 #[derive(Default)]
 struct CipherAesGcm {
     key: [u8; 32],
 }
 
+/// Wraps `aes-gcm`'s AES128-GCM implementation, keyed with the first 16 bytes of the
+/// Noise cipher key.
+#[cfg(feature = "aes128gcm")]
+#[derive(Default)]
+struct CipherAes128Gcm {
+    key: [u8; 16],
+}
+
 /// Wraps `chacha20_poly1305_aead`'s ChaCha20Poly1305 implementation.
 #[derive(Default)]
 struct CipherChaChaPoly {
@@ -106,15 +152,38 @@ struct HashSHA512 {
 }
 
 /// Wraps `blake2-rfc`'s implementation.
+#[derive(Default)]
 struct HashBLAKE2b {
     hasher: Blake2b,
 }
 
 /// Wraps `blake2-rfc`'s implementation.
+#[derive(Default)]
 struct HashBLAKE2s {
     hasher: Blake2s,
 }
 
+/// Wraps the `blake3` crate's implementation, using its default 32-byte output.
+#[cfg(feature = "blake3")]
+#[derive(Default)]
+struct HashBLAKE3 {
+    hasher: blake3::Hasher,
+}
+
+/// Wraps `RustCrypto`'s SHA3-256 implementation.
+#[cfg(feature = "sha3")]
+#[derive(Default)]
+struct HashSHA3_256 {
+    hasher: Sha3_256,
+}
+
+/// Wraps `RustCrypto`'s SHA3-512 implementation.
+#[cfg(feature = "sha3")]
+#[derive(Default)]
+struct HashSHA3_512 {
+    hasher: Sha3_512,
+}
+
 /// Wraps `kyber1024`'s implementation
 #[cfg(feature = "pqclean_kyber1024")]
 struct Kyber1024 {
@@ -122,6 +191,7 @@ struct Kyber1024 {
     pubkey:  kyber1024::PublicKey,
 }
 
+#[cfg(feature = "std")]
 impl Random for OsRng {}
 
 impl Dh for Dh25519 {
@@ -139,11 +209,23 @@ impl Dh for Dh25519 {
 
     fn set(&mut self, privkey: &[u8]) {
         copy_slices!(privkey, &mut self.privkey);
+        clamp_25519(&mut self.privkey);
         self.pubkey = x25519::x25519(self.privkey, x25519::X25519_BASEPOINT_BYTES);
     }
 
+    fn set_keypair(&mut self, privkey: &[u8], pubkey: &[u8]) {
+        copy_slices!(privkey, &mut self.privkey);
+        clamp_25519(&mut self.privkey);
+        copy_slices!(pubkey, &mut self.pubkey);
+        debug_assert!(
+            self.pubkey[..] == x25519::x25519(self.privkey, x25519::X25519_BASEPOINT_BYTES)[..],
+            "public key doesn't match the private key"
+        );
+    }
+
     fn generate(&mut self, rng: &mut dyn Random) {
         rng.fill_bytes(&mut self.privkey);
+        clamp_25519(&mut self.privkey);
         self.pubkey = x25519::x25519(self.privkey, x25519::X25519_BASEPOINT_BYTES);
     }
 
@@ -156,12 +238,249 @@ impl Dh for Dh25519 {
     }
 
     fn dh(&self, pubkey: &[u8], out: &mut [u8]) -> Result<(), ()> {
-        let result = x25519::x25519(self.privkey, pubkey[..32].try_into().unwrap());
+        let pubkey: [u8; 32] = pubkey.get(..32).ok_or(())?.try_into().map_err(|_| ())?;
+        if is_low_order_25519(&pubkey) {
+            return Err(());
+        }
+        let result = x25519::x25519(self.privkey, pubkey);
         copy_slices!(&result, out);
         Ok(())
     }
 }
 
+/// Curve25519 points of small order (and their non-canonical encodings, modulo 2^255-19), as
+/// blacklisted by libsodium. Any of these would force the shared secret to a value the peer can
+/// predict.
+const LOW_ORDER_25519: [[u8; 32]; 7] = [
+    // 0 (order 4)
+    [
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00,
+    ],
+    // 1 (order 1)
+    [
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00,
+    ],
+    // 325606250916557431795983626356110631294008115727848805560023387167927233504 (order 8)
+    [
+        0xe0, 0xeb, 0x7a, 0x7c, 0x3b, 0x41, 0xb8, 0xae, 0x16, 0x56, 0xe3, 0xfa, 0xf1, 0x9f, 0xc4,
+        0x6a, 0xda, 0x09, 0x8d, 0xeb, 0x9c, 0x32, 0xb1, 0xfd, 0x86, 0x62, 0x05, 0x16, 0x5f, 0x49,
+        0xb8, 0x00,
+    ],
+    // 39382357235489614581723060781553021112529911719440698176882885853963445705823 (order 8)
+    [
+        0x5f, 0x9c, 0x95, 0xbc, 0xa3, 0x50, 0x8c, 0x24, 0xb1, 0xd0, 0xb1, 0x55, 0x9c, 0x83, 0xef,
+        0x5b, 0x04, 0x44, 0x5c, 0xc4, 0x58, 0x1c, 0x8e, 0x86, 0xd8, 0x22, 0x4e, 0xdd, 0xd0, 0x9f,
+        0x11, 0x57,
+    ],
+    // p-1 (order 2)
+    [
+        0xec, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+    // p (=0, order 4)
+    [
+        0xed, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+    // p+1 (=1, order 1)
+    [
+        0xee, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x7f,
+    ],
+];
+
+/// Clears bits 0-2 and 255 and sets bit 254 of a private key, per RFC 7748. `x25519()` does
+/// the same to its scalar anyway; doing it up front means `privkey()` returns the scalar
+/// that's actually used.
+fn clamp_25519(privkey: &mut [u8; 32]) {
+    privkey[0] &= 0b1111_1000;
+    privkey[31] &= 0b0111_1111;
+    privkey[31] |= 0b0100_0000;
+}
+
+/// X25519 ignores the top bit of a public key, so it is masked off before comparing.
+fn is_low_order_25519(pubkey: &[u8; 32]) -> bool {
+    LOW_ORDER_25519
+        .iter()
+        .any(|point| point[..31] == pubkey[..31] && point[31] == pubkey[31] & 0x7f)
+}
+
+impl Drop for Dh25519 {
+    fn drop(&mut self) {
+        self.privkey.zeroize();
+    }
+}
+
+/// Curve25519 with the public key sent as its Elligator2 representative, which is
+/// indistinguishable from 32 random bytes, e.g. for censorship-resistant transports. Meant for
+/// ephemeral keys, see [`ElligatorResolver`](super::ElligatorResolver): both peers have to use
+/// it, while static keys stay plain X25519 keys.
+///
+/// Only about half of the keys have a representative, so [`generate()`](Dh::generate) draws
+/// keys until one does, and the builder rejects given keys without one. The public key is the "dirty" point `[k]B + T`, where `T` is a
+/// low-order point picked by the low bits of `k`, as otherwise decoded representatives would
+/// always land in the prime-order subgroup. X25519 clamps scalars to multiples of the
+/// cofactor, so the shared secret is the usual one.
+///
+/// The private key is kept unclamped, as its low bits pick `T`.
+#[cfg(feature = "elligator2")]
+#[derive(Default)]
+pub struct Dh25519Elligator {
+    privkey:        [u8; 32],
+    representative: [u8; 32],
+}
+
+#[cfg(feature = "elligator2")]
+impl Dh25519Elligator {
+    /// The representative of `privkey`'s public key, with `tweak` filling in the two unused
+    /// top bits and picking one of the two representatives.
+    fn representative(privkey: &[u8; 32], tweak: u8) -> Option<[u8; 32]> {
+        Randomized::to_representative(privkey, tweak).into()
+    }
+}
+
+#[cfg(feature = "elligator2")]
+impl Dh for Dh25519Elligator {
+    fn name(&self) -> &'static str {
+        "25519"
+    }
+
+    fn pub_len(&self) -> usize {
+        32
+    }
+
+    fn priv_len(&self) -> usize {
+        32
+    }
+
+    /// The two top bits of `privkey`, which X25519 ignores, become the top bits of the
+    /// representative. A key without one, see [`accepts_privkey()`](Dh::accepts_privkey), gets
+    /// an all-zero representative, which decodes to a low-order point that peers reject.
+    fn set(&mut self, privkey: &[u8]) {
+        copy_slices!(privkey, &mut self.privkey);
+        self.representative = Self::representative(&self.privkey, self.privkey[31] & 0xc0)
+            .unwrap_or([0u8; 32]);
+    }
+
+    fn accepts_privkey(&self, privkey: &[u8]) -> bool {
+        let privkey: Result<&[u8; 32], _> = privkey.try_into();
+        privkey.is_ok_and(|privkey| Self::representative(privkey, 0).is_some())
+    }
+
+    fn generate(&mut self, rng: &mut dyn Random) {
+        loop {
+            rng.fill_bytes(&mut self.privkey);
+            let tweak = rng.next_u32() as u8;
+            if let Some(representative) = Self::representative(&self.privkey, tweak) {
+                self.representative = representative;
+                return;
+            }
+        }
+    }
+
+    fn pubkey(&self) -> &[u8] {
+        &self.representative
+    }
+
+    fn privkey(&self) -> &[u8] {
+        &self.privkey
+    }
+
+    fn dh(&self, pubkey: &[u8], out: &mut [u8]) -> Result<(), ()> {
+        let pubkey: [u8; 32] = pubkey.get(..32).ok_or(())?.try_into().map_err(|_| ())?;
+        if is_low_order_25519(&pubkey) {
+            return Err(());
+        }
+        let result = x25519::x25519(self.privkey, pubkey);
+        copy_slices!(&result, out);
+        Ok(())
+    }
+
+    fn decode_pubkey(&self, encoded: &[u8], out: &mut [u8]) -> Result<(), ()> {
+        let representative: [u8; 32] = encoded.try_into().map_err(|_| ())?;
+        let point = MontgomeryPoint::from_representative::<Randomized>(&representative).ok_or(())?;
+        copy_slices!(point.to_bytes(), out);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "elligator2")]
+impl Drop for Dh25519Elligator {
+    fn drop(&mut self) {
+        self.privkey.zeroize();
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for Dh448 {
+    fn default() -> Dh448 {
+        Dh448 { privkey: [0u8; 56], pubkey: [0u8; 56] }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Dh for Dh448 {
+    fn name(&self) -> &'static str {
+        "448"
+    }
+
+    fn pub_len(&self) -> usize {
+        56
+    }
+
+    fn priv_len(&self) -> usize {
+        56
+    }
+
+    fn set(&mut self, privkey: &[u8]) {
+        copy_slices!(privkey, &mut self.privkey);
+        self.pubkey = x448::x448_unchecked(self.privkey, x448::X448_BASEPOINT_BYTES);
+    }
+
+    fn set_keypair(&mut self, privkey: &[u8], pubkey: &[u8]) {
+        copy_slices!(privkey, &mut self.privkey);
+        copy_slices!(pubkey, &mut self.pubkey);
+        debug_assert!(
+            self.pubkey[..] == x448::x448_unchecked(self.privkey, x448::X448_BASEPOINT_BYTES)[..],
+            "public key doesn't match the private key"
+        );
+    }
+
+    fn generate(&mut self, rng: &mut dyn Random) {
+        rng.fill_bytes(&mut self.privkey);
+        self.pubkey = x448::x448_unchecked(self.privkey, x448::X448_BASEPOINT_BYTES);
+    }
+
+    fn pubkey(&self) -> &[u8] {
+        &self.pubkey
+    }
+
+    fn privkey(&self) -> &[u8] {
+        &self.privkey
+    }
+
+    fn dh(&self, pubkey: &[u8], out: &mut [u8]) -> Result<(), ()> {
+        let pubkey: [u8; 56] = pubkey.get(..56).ok_or(())?.try_into().map_err(|_| ())?;
+        let result = x448::x448(self.privkey, pubkey).ok_or(())?;
+        copy_slices!(&result, out);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for Dh448 {
+    fn drop(&mut self) {
+        self.privkey.zeroize();
+    }
+}
+
 impl Cipher for CipherAesGcm {
     fn name(&self) -> &'static str {
         "AESGCM"
@@ -172,20 +491,98 @@ impl Cipher for CipherAesGcm {
     }
 
     fn encrypt(&self, nonce: u64, authtext: &[u8], plaintext: &[u8], out: &mut [u8]) -> usize {
+        copy_slices!(plaintext, out);
+        self.encrypt_in_place(nonce, authtext, out, plaintext.len())
+    }
+
+    fn encrypt_in_place(
+        &self,
+        nonce: u64,
+        authtext: &[u8],
+        in_out: &mut [u8],
+        plaintext_len: usize,
+    ) -> usize {
         let aead = aes_gcm::Aes256Gcm::new(&self.key.into());
 
         let mut nonce_bytes = [0u8; 12];
         copy_slices!(&nonce.to_be_bytes(), &mut nonce_bytes[4..]);
 
+        let tag = aead
+            .encrypt_in_place_detached(&nonce_bytes.into(), authtext, &mut in_out[..plaintext_len])
+            .expect("Encryption failed!");
+
+        copy_slices!(tag, &mut in_out[plaintext_len..]);
+
+        plaintext_len + TAGLEN
+    }
+
+    fn decrypt(
+        &self,
+        nonce: u64,
+        authtext: &[u8],
+        ciphertext: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, ()> {
+        let aead = aes_gcm::Aes256Gcm::new(&self.key.into());
+
+        let mut nonce_bytes = [0u8; 12];
+        copy_slices!(&nonce.to_be_bytes(), &mut nonce_bytes[4..]);
+
+        let message_len = ciphertext.len() - TAGLEN;
+
+        copy_slices!(ciphertext[..message_len], out);
+
+        aead.decrypt_in_place_detached(
+            &nonce_bytes.into(),
+            authtext,
+            &mut out[..message_len],
+            ciphertext[message_len..].into(),
+        )
+        .map(|_| message_len)
+        .map_err(|_| ())
+    }
+}
+
+impl Drop for CipherAesGcm {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+#[cfg(feature = "aes128gcm")]
+impl Cipher for CipherAes128Gcm {
+    fn name(&self) -> &'static str {
+        "AES128GCM"
+    }
+
+    fn set(&mut self, key: &[u8]) {
+        copy_slices!(key[..16], &mut self.key)
+    }
+
+    fn encrypt(&self, nonce: u64, authtext: &[u8], plaintext: &[u8], out: &mut [u8]) -> usize {
         copy_slices!(plaintext, out);
+        self.encrypt_in_place(nonce, authtext, out, plaintext.len())
+    }
+
+    fn encrypt_in_place(
+        &self,
+        nonce: u64,
+        authtext: &[u8],
+        in_out: &mut [u8],
+        plaintext_len: usize,
+    ) -> usize {
+        let aead = aes_gcm::Aes128Gcm::new(&self.key.into());
+
+        let mut nonce_bytes = [0u8; 12];
+        copy_slices!(&nonce.to_be_bytes(), &mut nonce_bytes[4..]);
 
         let tag = aead
-            .encrypt_in_place_detached(&nonce_bytes.into(), authtext, &mut out[0..plaintext.len()])
+            .encrypt_in_place_detached(&nonce_bytes.into(), authtext, &mut in_out[..plaintext_len])
             .expect("Encryption failed!");
 
-        copy_slices!(tag, &mut out[plaintext.len()..]);
+        copy_slices!(tag, &mut in_out[plaintext_len..]);
 
-        plaintext.len() + TAGLEN
+        plaintext_len + TAGLEN
     }
 
     fn decrypt(
@@ -195,7 +592,7 @@ impl Cipher for CipherAesGcm {
         ciphertext: &[u8],
         out: &mut [u8],
     ) -> Result<usize, ()> {
-        let aead = aes_gcm::Aes256Gcm::new(&self.key.into());
+        let aead = aes_gcm::Aes128Gcm::new(&self.key.into());
 
         let mut nonce_bytes = [0u8; 12];
         copy_slices!(&nonce.to_be_bytes(), &mut nonce_bytes[4..]);
@@ -215,6 +612,13 @@ impl Cipher for CipherAesGcm {
     }
 }
 
+#[cfg(feature = "aes128gcm")]
+impl Drop for CipherAes128Gcm {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
 impl Cipher for CipherChaChaPoly {
     fn name(&self) -> &'static str {
         "ChaChaPoly"
@@ -225,18 +629,27 @@ impl Cipher for CipherChaChaPoly {
     }
 
     fn encrypt(&self, nonce: u64, authtext: &[u8], plaintext: &[u8], out: &mut [u8]) -> usize {
+        copy_slices!(plaintext, out);
+        self.encrypt_in_place(nonce, authtext, out, plaintext.len())
+    }
+
+    fn encrypt_in_place(
+        &self,
+        nonce: u64,
+        authtext: &[u8],
+        in_out: &mut [u8],
+        plaintext_len: usize,
+    ) -> usize {
         let mut nonce_bytes = [0u8; 12];
         copy_slices!(&nonce.to_le_bytes(), &mut nonce_bytes[4..]);
 
-        copy_slices!(plaintext, out);
-
         let tag = ChaCha20Poly1305::new(&self.key.into())
-            .encrypt_in_place_detached(&nonce_bytes.into(), authtext, &mut out[0..plaintext.len()])
+            .encrypt_in_place_detached(&nonce_bytes.into(), authtext, &mut in_out[..plaintext_len])
             .unwrap();
 
-        copy_slices!(tag, &mut out[plaintext.len()..]);
+        copy_slices!(tag, &mut in_out[plaintext_len..]);
 
-        plaintext.len() + tag.len()
+        plaintext_len + tag.len()
     }
 
     fn decrypt(
@@ -267,6 +680,12 @@ impl Cipher for CipherChaChaPoly {
     }
 }
 
+impl Drop for CipherChaChaPoly {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
 #[cfg(feature = "xchachapoly")]
 impl Cipher for CipherXChaChaPoly {
     fn name(&self) -> &'static str {
@@ -278,18 +697,27 @@ impl Cipher for CipherXChaChaPoly {
     }
 
     fn encrypt(&self, nonce: u64, authtext: &[u8], plaintext: &[u8], out: &mut [u8]) -> usize {
+        copy_slices!(plaintext, out);
+        self.encrypt_in_place(nonce, authtext, out, plaintext.len())
+    }
+
+    fn encrypt_in_place(
+        &self,
+        nonce: u64,
+        authtext: &[u8],
+        in_out: &mut [u8],
+        plaintext_len: usize,
+    ) -> usize {
         let mut nonce_bytes = [0u8; 24];
         copy_slices!(&nonce.to_le_bytes(), &mut nonce_bytes[16..]);
 
-        copy_slices!(plaintext, out);
-
         let tag = XChaCha20Poly1305::new(&self.key.into())
-            .encrypt_in_place_detached(&nonce_bytes.into(), authtext, &mut out[0..plaintext.len()])
+            .encrypt_in_place_detached(&nonce_bytes.into(), authtext, &mut in_out[..plaintext_len])
             .unwrap();
 
-        copy_slices!(tag, &mut out[plaintext.len()..]);
+        copy_slices!(tag, &mut in_out[plaintext_len..]);
 
-        plaintext.len() + tag.len()
+        plaintext_len + tag.len()
     }
 
     fn decrypt(
@@ -320,6 +748,13 @@ impl Cipher for CipherXChaChaPoly {
     }
 }
 
+#[cfg(feature = "xchachapoly")]
+impl Drop for CipherXChaChaPoly {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
 impl Default for HashSHA256 {
     fn default() -> HashSHA256 {
         HashSHA256 { hasher: Sha256::new() }
@@ -349,7 +784,7 @@ impl Hash for HashSHA256 {
 
     fn result(&mut self, out: &mut [u8]) {
         let hash = self.hasher.finalize_reset();
-        copy_slices!(hash.as_slice(), out)
+        copy_slices!(hash, out)
     }
 }
 
@@ -382,13 +817,7 @@ impl Hash for HashSHA512 {
 
     fn result(&mut self, out: &mut [u8]) {
         let hash = self.hasher.finalize_reset();
-        copy_slices!(hash.as_slice(), out)
-    }
-}
-
-impl Default for HashBLAKE2b {
-    fn default() -> HashBLAKE2b {
-        HashBLAKE2b { hasher: Blake2b::default() }
+        copy_slices!(hash, out)
     }
 }
 
@@ -419,12 +848,6 @@ impl Hash for HashBLAKE2b {
     }
 }
 
-impl Default for HashBLAKE2s {
-    fn default() -> HashBLAKE2s {
-        HashBLAKE2s { hasher: Blake2s::default() }
-    }
-}
-
 impl Hash for HashBLAKE2s {
     fn name(&self) -> &'static str {
         "BLAKE2s"
@@ -452,6 +875,92 @@ impl Hash for HashBLAKE2s {
     }
 }
 
+#[cfg(feature = "blake3")]
+impl Hash for HashBLAKE3 {
+    fn name(&self) -> &'static str {
+        "BLAKE3"
+    }
+
+    fn block_len(&self) -> usize {
+        64
+    }
+
+    fn hash_len(&self) -> usize {
+        32
+    }
+
+    fn reset(&mut self) {
+        self.hasher.reset();
+    }
+
+    fn input(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    fn result(&mut self, out: &mut [u8]) {
+        let hash = self.hasher.finalize();
+        self.hasher.reset();
+        out[..32].copy_from_slice(hash.as_bytes());
+    }
+}
+
+// The HMAC block length of a SHA-3 hash is its sponge rate.
+#[cfg(feature = "sha3")]
+impl Hash for HashSHA3_256 {
+    fn name(&self) -> &'static str {
+        "SHA3/256"
+    }
+
+    fn block_len(&self) -> usize {
+        136
+    }
+
+    fn hash_len(&self) -> usize {
+        32
+    }
+
+    fn reset(&mut self) {
+        self.hasher = Sha3_256::new();
+    }
+
+    fn input(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    fn result(&mut self, out: &mut [u8]) {
+        let hash = self.hasher.finalize_reset();
+        copy_slices!(hash, out)
+    }
+}
+
+#[cfg(feature = "sha3")]
+impl Hash for HashSHA3_512 {
+    fn name(&self) -> &'static str {
+        "SHA3/512"
+    }
+
+    fn block_len(&self) -> usize {
+        72
+    }
+
+    fn hash_len(&self) -> usize {
+        64
+    }
+
+    fn reset(&mut self) {
+        self.hasher = Sha3_512::new();
+    }
+
+    fn input(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    fn result(&mut self, out: &mut [u8]) {
+        let hash = self.hasher.finalize_reset();
+        copy_slices!(hash, out)
+    }
+}
+
 #[cfg(feature = "pqclean_kyber1024")]
 impl Default for Kyber1024 {
     fn default() -> Self {
@@ -523,6 +1032,7 @@ impl Kem for Kyber1024 {
 
 #[cfg(test)]
 mod tests {
+    use alloc::{vec, vec::Vec};
     use hex::FromHex;
     use super::*;
 
@@ -554,7 +1064,7 @@ mod tests {
         let mut hasher: HashSHA512 = Default::default();
         hasher.hmac(&key, &data, &mut output2);
         assert!(
-            hex::encode(output2.to_vec())
+            hex::encode(output2)
                 == "fa73b0089d56a284efb0f0756c890be9\
                                      b1b5dbdd8ee81a3655f83e33b2279d39\
                                      bf3e848279a722c806b485a47e67c807\
@@ -562,6 +1072,93 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_hkdf_output_counts() {
+        let chaining_key = [0u8; 32];
+        let ikm = [1u8; 32];
+        let mut hasher: HashSHA256 = Default::default();
+        let mut full = ([0u8; 32], [0u8; 32], [0u8; 32]);
+        hasher.hkdf(&chaining_key, &ikm, 3, &mut full.0, &mut full.1, &mut full.2);
+
+        // Fewer outputs are a prefix of more, and leave the unused buffers alone.
+        for outputs in 1..=2 {
+            let mut out = ([0u8; 32], [0xffu8; 32], [0xffu8; 32]);
+            hasher.hkdf(&chaining_key, &ikm, outputs, &mut out.0, &mut out.1, &mut out.2);
+            assert_eq!(out.0, full.0);
+            assert_eq!(out.1, if outputs == 2 { full.1 } else { [0xff; 32] });
+            assert_eq!(out.2, [0xff; 32]);
+        }
+        assert_ne!(full.0, full.1);
+        assert_ne!(full.1, full.2);
+    }
+
+    #[test]
+    fn test_hkdf_64_byte_hashes() {
+        // Full-length chaining key and input key material, checked against HMAC-based HKDF
+        // from Python's hmac module.
+        let chaining_key: Vec<u8> = (0u8..64).collect();
+        let ikm: Vec<u8> = (64u8..128).collect();
+        let mut out = ([0u8; 64], [0u8; 64], [0u8; 64]);
+        let mut hasher: HashSHA512 = Default::default();
+        hasher.hkdf(&chaining_key, &ikm, 3, &mut out.0, &mut out.1, &mut out.2);
+        assert_eq!(
+            hex::encode(out.0),
+            "97d1116dd23e2fdeb946a8924ea1090d2b53e191cca0ea329942dd3511bdad4c\
+             f8392dea9076aee73d941be212b30cfc959df356f2eee6091fdef17049fdd321"
+        );
+        assert_eq!(
+            hex::encode(out.1),
+            "9c925804aec725037b8f3502bc67109677a712f9aaa360b41d0355fdc5e65032\
+             35b38ca17118520520b28cc8a8483d6d4863917dfadb501284dab4f448bf0a7b"
+        );
+        assert_eq!(
+            hex::encode(out.2),
+            "6f8b4797c54eac6a8f6dd2ed3e7d08f5bfdc1c210b9d1d364b0b26901c92348e\
+             296d87b377ba7dad94c06d70ba98118717660745bfa2165ce45bbb71d5533f71"
+        );
+
+        let mut hasher: HashBLAKE2b = Default::default();
+        hasher.hkdf(&chaining_key, &ikm, 3, &mut out.0, &mut out.1, &mut out.2);
+        assert_eq!(
+            hex::encode(out.0),
+            "a759c34c7fa27626a085e28e50f146a2117cb9ffcb6830bbe02ab6df5eed468b\
+             23a253a6bd2c75f9277b1e8ff4cb20a84a331c2cdd5bac944b090e346c3e8b48"
+        );
+        assert_eq!(
+            hex::encode(out.1),
+            "fc32bd7dfdde42b753c8cbd45f8132d075ef414557177f7cd79631b0eec5dbdb\
+             762009d90cba8cc9b8a1d411844df5cc9652eb5a0c41583da9c4c4f6d61ea569"
+        );
+        assert_eq!(
+            hex::encode(out.2),
+            "f802512da3b700817ace47280143668c40055ac1d596598666ba6798fbb423bb\
+             60ebf1400dc9ff6179c69f960c7f67053fa35d3f4affaad70c47f3cbc4556f46"
+        );
+    }
+
+    #[test]
+    fn test_hmac_block_len_key() {
+        // A key of exactly the block length fills the pads without being hashed first.
+        let key = [0xaau8; 128];
+        let data = b"Test Using Larger Than Block-Size Key";
+        let mut output = [0u8; 64];
+        let mut hasher: HashSHA512 = Default::default();
+        hasher.hmac(&key, data, &mut output);
+        assert_eq!(
+            hex::encode(output),
+            "dc36ee4171c5c8d56e953b59370c2708ac6cfb61d66bc66346937249bf582ae1\
+             60f7d53ab053586a66f3fe58df79473fe442661fa2cad5f2efb18a91e2f5c170"
+        );
+
+        let mut hasher: HashBLAKE2b = Default::default();
+        hasher.hmac(&key, data, &mut output);
+        assert_eq!(
+            hex::encode(output),
+            "ab7b17a6a4991fb244c2ccdca103084577eb82e294367d2ad1c1414df2039d1f\
+             8e452ec6747e15fdbc50a41279c97d5069eac500539c6f493f03df3302f6ed7a"
+        );
+    }
+
     #[test]
     fn test_blake2b() {
         // BLAKE2b test - draft-saarinen-blake2-06
@@ -570,7 +1167,7 @@ mod tests {
         hasher.input(b"abc");
         hasher.result(&mut output);
         assert!(
-            hex::encode(output.to_vec())
+            hex::encode(output)
                 == "ba80a53f981c4d0d6a2797b69f12f6e9\
                                     4c212f14685ac4b74b12bb6fdbffa2d1\
                                     7d87c5392aab792dc252d5de4533cc95\
@@ -592,6 +1189,109 @@ mod tests {
         );
     }
 
+    #[test]
+    #[cfg(feature = "blake3")]
+    fn test_blake3() {
+        // BLAKE3 test - "abc", default 32-byte output
+        let mut output = [0u8; 32];
+        let mut hasher: HashBLAKE3 = Default::default();
+        hasher.input(b"abc");
+        hasher.result(&mut output);
+        assert!(
+            hex::encode(output)
+                == "6437b3ac38465133ffb63b75273a8db5\
+                    48c558465d79db03fd359c6cd5bd9d85"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "blake3")]
+    fn test_hkdf_blake3() {
+        let chaining_key = [0u8; 32];
+        let ikm: Vec<u8> = (0u8..32).collect();
+        let mut out1 = [0u8; 32];
+        let mut out2 = [0u8; 32];
+        let mut out3 = [0u8; 32];
+        let mut hasher: HashBLAKE3 = Default::default();
+        hasher.hkdf(&chaining_key, &ikm, 3, &mut out1, &mut out2, &mut out3);
+        assert_eq!(
+            hex::encode(out1),
+            "2a3ca130fede2386234ea53a34eace15431c4e122ae8f41a97f5ac5da15a7c2c"
+        );
+        assert_eq!(
+            hex::encode(out2),
+            "f5e7626884e318ea8604af3cc2288058c36ba10577b841d36bbc52af9ee982aa"
+        );
+        assert_eq!(
+            hex::encode(out3),
+            "b349e2038d4a717007433539f99f6160e286d5b80f6ce1942c4c044f986eb745"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "sha3")]
+    fn test_sha3() {
+        // SHA3 tests - FIPS 202 "abc"
+        let mut output = [0u8; 64];
+        let mut hasher: HashSHA3_256 = Default::default();
+        hasher.input(b"abc");
+        hasher.result(&mut output[..32]);
+        assert_eq!(
+            hex::encode(&output[..32]),
+            "3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532"
+        );
+
+        let mut hasher: HashSHA3_512 = Default::default();
+        hasher.input(b"abc");
+        hasher.result(&mut output);
+        assert_eq!(
+            hex::encode(output),
+            "b751850b1a57168a5693cd924b6b096e08f621827444f70d884f5d0240d2712e\
+             10e116e9192af3c91a7ec57647e3934057340b4cf408d5a56592f8274eec53f0"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "sha3")]
+    fn test_hkdf_sha3() {
+        // Both block lengths differ from the SHA-2 and BLAKE2 ones, so check the HMAC padding.
+        let chaining_key = [0u8; 32];
+        let ikm: Vec<u8> = (0u8..32).collect();
+        let mut out1 = [0u8; 32];
+        let mut out2 = [0u8; 32];
+        let mut out3 = [0u8; 32];
+        let mut hasher: HashSHA3_256 = Default::default();
+        hasher.hkdf(&chaining_key, &ikm, 3, &mut out1, &mut out2, &mut out3);
+        assert_eq!(
+            hex::encode(out1),
+            "3608c2308d4fbecbb6df3956e7e4ea93590e4be90381a033173402483dc6eb50"
+        );
+        assert_eq!(
+            hex::encode(out2),
+            "11e1d365cc4dc43054cc2846c9290b29e5729b49153e6072158d52a41823c671"
+        );
+        assert_eq!(
+            hex::encode(out3),
+            "e0c63423d05e53067dde5faff4ea815f4ad6530125ad0853a02b20ad8a6e3c99"
+        );
+
+        let chaining_key = [0u8; 64];
+        let mut out1 = [0u8; 64];
+        let mut out2 = [0u8; 64];
+        let mut hasher: HashSHA3_512 = Default::default();
+        hasher.hkdf(&chaining_key, &ikm, 2, &mut out1, &mut out2, &mut []);
+        assert_eq!(
+            hex::encode(out1),
+            "93a720279f48fd049f5f91d8e3fb751458bf63a07eeb3b5158d917d43c6988a2\
+             cef156745d1f97cdb93ed5fb525d9e49586842ce3abb29f3328d0775fb639169"
+        );
+        assert_eq!(
+            hex::encode(out2),
+            "ca700d727f2efe4dd5ec6d7c75914f0d064abd7f7333c0a2916f9d7d2c1573d0\
+             99c99e1a712821e2381efae630869e3c6ee4968134786aa3d81594c1b9f50fa9"
+        );
+    }
+
     #[test]
     fn test_curve25519() {
         // Curve25519 test - draft-curves-10
@@ -611,6 +1311,139 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_curve25519_clamping() {
+        struct OnesRng;
+        impl rand_core::RngCore for OnesRng {
+            fn next_u32(&mut self) -> u32 {
+                u32::MAX
+            }
+
+            fn next_u64(&mut self) -> u64 {
+                u64::MAX
+            }
+
+            fn fill_bytes(&mut self, dest: &mut [u8]) {
+                dest.fill(0xff);
+            }
+
+            fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+                self.fill_bytes(dest);
+                Ok(())
+            }
+        }
+        impl rand_core::CryptoRng for OnesRng {}
+        impl Random for OnesRng {}
+
+        let mut clamped = [0xffu8; 32];
+        clamped[0] = 0xf8;
+        clamped[31] = 0x7f;
+        // Computed independently with Python's `cryptography`.
+        let public = "847c0d2c375234f365e660955187a3735a0f7613d1609d3a6a4d8c53aeaa5a22";
+
+        let mut set: Dh25519 = Default::default();
+        set.set(&[0xff; 32]);
+        let mut generated: Dh25519 = Default::default();
+        generated.generate(&mut OnesRng);
+        let mut keypair: Dh25519 = Default::default();
+        keypair.set_keypair(&[0xff; 32], &Vec::<u8>::from_hex(public).unwrap());
+        for dh in [&set, &generated, &keypair] {
+            assert_eq!(dh.privkey(), &clamped[..]);
+            assert_eq!(hex::encode(dh.pubkey()), public);
+        }
+
+        let mut other: Dh25519 = Default::default();
+        other.set(&[0x42; 32]);
+        let (mut out1, mut out2) = ([0u8; 32], [0u8; 32]);
+        set.dh(other.pubkey(), &mut out1).unwrap();
+        other.dh(set.pubkey(), &mut out2).unwrap();
+        assert_eq!(out1, out2);
+        assert_eq!(out1, x25519::x25519(clamped, other.pubkey));
+    }
+
+    #[test]
+    #[cfg(all(feature = "elligator2", feature = "std"))]
+    fn test_curve25519_elligator() {
+        let mut plain: Dh25519 = Default::default();
+        plain.set(&[0x42; 32]);
+        let (mut point, mut out1, mut out2) = ([0u8; 32], [0u8; 32], [0u8; 32]);
+        for _ in 0..16 {
+            let mut keypair: Dh25519Elligator = Default::default();
+            keypair.generate(&mut OsRng);
+            keypair.decode_pubkey(keypair.pubkey(), &mut point).unwrap();
+            assert_ne!(&point[..], keypair.pubkey());
+            keypair.dh(plain.pubkey(), &mut out1).unwrap();
+            plain.dh(&point, &mut out2).unwrap();
+            assert_eq!(out1, out2);
+        }
+
+        // Only some keys are accepted, and their unused top bits end up in the representative.
+        let mut keypair: Dh25519Elligator = Default::default();
+        let (accepted, rejected): (Vec<_>, Vec<_>) =
+            (0xc0..=0xff).map(|i| [i; 32]).partition(|k| keypair.accepts_privkey(k));
+        assert!(!keypair.accepts_privkey(&[0xc0; 31]));
+
+        // Setting a rejected key anyway doesn't panic, but leaves a public key peers reject.
+        keypair.set(&rejected[0]);
+        keypair.decode_pubkey(keypair.pubkey(), &mut point).unwrap();
+        assert!(plain.dh(&point, &mut out2).is_err());
+
+        let privkey = accepted[0];
+        keypair.set(&privkey);
+        assert_eq!(keypair.privkey(), &privkey[..]);
+        assert_eq!(keypair.pubkey()[31] & 0xc0, 0xc0);
+        keypair.decode_pubkey(keypair.pubkey(), &mut point).unwrap();
+        keypair.dh(plain.pubkey(), &mut out1).unwrap();
+        plain.dh(&point, &mut out2).unwrap();
+        assert_eq!(out1, out2);
+    }
+
+    #[test]
+    fn test_curve25519_low_order() {
+        let mut keypair: Dh25519 = Default::default();
+        keypair.set(&[0x42; 32]);
+        let mut output = [0u8; 32];
+        for point in LOW_ORDER_25519.iter() {
+            let mut high = *point;
+            high[31] |= 0x80;
+            for pubkey in [point, &high] {
+                // Every blacklisted point forces an all-zero shared secret...
+                assert_eq!(x25519::x25519(keypair.privkey, *pubkey), [0u8; 32]);
+                // ...and so is rejected.
+                assert!(keypair.dh(pubkey, &mut output).is_err());
+            }
+        }
+        assert!(keypair.dh(&x25519::X25519_BASEPOINT_BYTES, &mut output).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_curve448() {
+        // Curve448 test - RFC 7748
+        let mut keypair: Dh448 = Default::default();
+        let scalar = Vec::<u8>::from_hex(
+            "3d262fddf9ec8e88495266fea19a34d28882acef045104d0d1aae121700a779c\
+             984c24f8cdd78fbff44943eba368f54b29259a4f1c600ad3",
+        )
+        .unwrap();
+        keypair.set(&scalar);
+        let public = Vec::<u8>::from_hex(
+            "06fce640fa3487bfda5f6cf2d5263f8aad88334cbd07437f020f08f9814dc031\
+             ddbdc38c19c6da2583fa5429db94ada18aa7a7fb4ef8a086",
+        )
+        .unwrap();
+        let mut output = [0u8; 56];
+        keypair.dh(&public, &mut output).unwrap();
+        assert!(
+            hex::encode(output)
+                == "ce3e4ff95a60dc6697da1db1d85e6afbdf79b50a2412d7546d5f239fe14fbaad\
+                    eb445fc66a01b0779d98223961111e21766282f73dd96b6f"
+        );
+
+        // A short public key is an error, not a panic.
+        assert!(keypair.dh(&public[..32], &mut output).is_err());
+    }
+
     #[test]
     fn test_aesgcm() {
         // AES256-GCM tests - gcm-spec.pdf
@@ -653,6 +1486,74 @@ mod tests {
         assert!(cipher4.decrypt(nonce, &authtext, &ciphertext2, &mut resulttext2).is_err());
     }
 
+    #[test]
+    fn test_aesgcm_multiblock() {
+        // Long enough for the backends selected at runtime to process blocks in parallel,
+        // with a nonce and AD that aren't zero. Computed with Python's `cryptography`.
+        let key: Vec<u8> = (0..32).collect();
+        let plaintext: Vec<u8> = (0..300).map(|i| (i % 251) as u8).collect();
+        let mut cipher: CipherAesGcm = Default::default();
+        cipher.set(&key);
+        let mut ciphertext = [0u8; 316];
+        assert_eq!(cipher.encrypt(0x0102030405060708, b"snow", &plaintext, &mut ciphertext), 316);
+
+        let mut hasher: HashSHA256 = Default::default();
+        let mut digest = [0u8; 32];
+        hasher.input(&ciphertext);
+        hasher.result(&mut digest);
+        assert_eq!(hex::encode(&ciphertext[..16]), "0450670dd9e40f990b96f7e4126a37ff");
+        assert_eq!(hex::encode(&ciphertext[300..]), "77ac328d5d05f3ea6f1707a4aeabebe2");
+        assert_eq!(
+            hex::encode(digest),
+            "a438d4219ca2d6dabcb8153e284a4bbe407db189e9260cf30ecc5c8cba5d41cc"
+        );
+
+        let mut in_place = plaintext.clone();
+        in_place.resize(316, 0);
+        cipher.encrypt_in_place(0x0102030405060708, b"snow", &mut in_place, 300);
+        assert_eq!(&in_place[..], &ciphertext[..]);
+        let mut decrypted = [0u8; 300];
+        cipher.decrypt(0x0102030405060708, b"snow", &ciphertext, &mut decrypted).unwrap();
+        assert_eq!(&decrypted[..], &plaintext[..]);
+    }
+
+    #[test]
+    #[cfg(feature = "aes128gcm")]
+    fn test_aes128gcm() {
+        // AES128-GCM tests - gcm-spec.pdf, keyed from the first half of a 32-byte Noise key
+        // Test Case 1
+        let key = [0u8; 32];
+        let nonce = 0u64;
+        let plaintext = [0u8; 0];
+        let authtext = [0u8; 0];
+        let mut ciphertext = [0u8; 16];
+        let mut cipher1: CipherAes128Gcm = Default::default();
+        cipher1.set(&key);
+        cipher1.encrypt(nonce, &authtext, &plaintext, &mut ciphertext);
+        assert!(hex::encode(ciphertext) == "58e2fccefa7e3061367f1d57a4e7455a");
+
+        let mut resulttext = [0u8; 1];
+        cipher1.decrypt(nonce, &authtext, &ciphertext, &mut resulttext).unwrap();
+        assert!(resulttext[0] == 0);
+        ciphertext[0] ^= 1;
+        assert!(cipher1.decrypt(nonce, &authtext, &ciphertext, &mut resulttext).is_err());
+
+        // Test Case 2
+        let plaintext2 = [0u8; 16];
+        let mut ciphertext2 = [0u8; 32];
+        let mut cipher2: CipherAes128Gcm = Default::default();
+        cipher2.set(&key);
+        cipher2.encrypt(nonce, &authtext, &plaintext2, &mut ciphertext2);
+        assert!(
+            hex::encode(ciphertext2)
+                == "0388dace60b6a392f328c2b971b2fe78ab6e47d42cec13bdf53a67b21257bddf"
+        );
+
+        let mut resulttext2 = [1u8; 16];
+        cipher2.decrypt(nonce, &authtext, &ciphertext2, &mut resulttext2).unwrap();
+        assert!(plaintext2 == resulttext2);
+    }
+
     #[test]
     fn test_chachapoly_empty() {
         //ChaChaPoly round-trip test, empty plaintext
@@ -690,7 +1591,7 @@ mod tests {
         let mut cipher2: CipherChaChaPoly = Default::default();
         cipher2.set(&key);
         cipher2.decrypt(nonce, &authtext, &ciphertext, &mut resulttext).unwrap();
-        assert!(hex::encode(resulttext.to_vec()) == hex::encode(plaintext.to_vec()));
+        assert!(hex::encode(resulttext) == hex::encode(plaintext));
     }
 
     #[cfg(feature = "xchachapoly")]
@@ -776,7 +1677,66 @@ mod tests {
                                  6d206f74686572207468616e20617320\
                                  2fe2809c776f726b20696e2070726f67\
                                  726573732e2fe2809d";
-        assert!(hex::encode(out[..ciphertext.len()].to_owned()) == desired_plaintext);
+        assert!(hex::encode(&out[..ciphertext.len()]) == desired_plaintext);
+    }
+
+    #[test]
+    fn test_encrypt_in_place() {
+        // Only implements the required methods, to exercise the trait's fallback.
+        #[derive(Default)]
+        struct Fallback(CipherChaChaPoly);
+        impl Cipher for Fallback {
+            fn name(&self) -> &'static str {
+                self.0.name()
+            }
+
+            fn set(&mut self, key: &[u8]) {
+                self.0.set(key)
+            }
+
+            fn encrypt(
+                &self,
+                nonce: u64,
+                authtext: &[u8],
+                plaintext: &[u8],
+                out: &mut [u8],
+            ) -> usize {
+                self.0.encrypt(nonce, authtext, plaintext, out)
+            }
+
+            fn decrypt(
+                &self,
+                nonce: u64,
+                authtext: &[u8],
+                ciphertext: &[u8],
+                out: &mut [u8],
+            ) -> Result<usize, ()> {
+                self.0.decrypt(nonce, authtext, ciphertext, out)
+            }
+        }
+
+        #[allow(unused_mut)]
+        let mut ciphers: Vec<Box<dyn Cipher>> = vec![
+            Box::new(CipherAesGcm::default()),
+            Box::new(CipherChaChaPoly::default()),
+            Box::new(Fallback::default()),
+        ];
+        #[cfg(feature = "aes128gcm")]
+        ciphers.push(Box::new(CipherAes128Gcm::default()));
+        #[cfg(feature = "xchachapoly")]
+        ciphers.push(Box::new(CipherXChaChaPoly::default()));
+
+        let plaintext = [0x34u8; 117];
+        for mut cipher in ciphers {
+            cipher.set(&[7u8; 32]);
+            let mut expected = [0u8; 133];
+            assert_eq!(cipher.encrypt(3, b"ad", &plaintext, &mut expected), 133);
+
+            let mut in_out = [0u8; 133];
+            in_out[..117].copy_from_slice(&plaintext);
+            assert_eq!(cipher.encrypt_in_place(3, b"ad", &mut in_out, 117), 133);
+            assert_eq!(in_out[..], expected[..], "{}", cipher.name());
+        }
     }
 
     #[test]
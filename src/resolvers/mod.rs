@@ -14,6 +14,7 @@ mod ring;
 use crate::params::KemChoice;
 #[cfg(feature = "hfs")]
 use crate::types::Kem;
+use alloc::boxed::Box;
 use crate::{
     params::{CipherChoice, DHChoice, HashChoice},
     types::{Cipher, Dh, Hash, Random},
@@ -21,11 +22,23 @@ use crate::{
 
 #[cfg(feature = "default-resolver")]
 pub use self::default::DefaultResolver;
+#[cfg(feature = "elligator2")]
+pub use self::default::Dh25519Elligator;
 #[cfg(feature = "libsodium-resolver")]
 pub use self::libsodium::SodiumResolver;
 #[cfg(feature = "ring-resolver")]
 pub use self::ring::RingResolver;
 
+/// Which of a session's keypairs a DH implementation is being resolved for.
+/// See [`CryptoResolver::resolve_dh_for()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DhRole {
+    /// The long-term static keypair, `s`.
+    Static,
+    /// The per-session ephemeral keypair, `e`.
+    Ephemeral,
+}
+
 /// Boxed CryptoResolver
 pub type BoxedCryptoResolver = Box<dyn CryptoResolver + Send>;
 
@@ -37,6 +50,14 @@ pub trait CryptoResolver {
     /// Provide an implementation of the Dh trait for the given DHChoice or None if unavailable.
     fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<dyn Dh>>;
 
+    /// Like `resolve_dh()`, but for the keypair of the given role, e.g. to keep the static
+    /// key in an HSM while ephemerals use a fast software implementation.
+    ///
+    /// Defaults to `resolve_dh()`, ignoring the role.
+    fn resolve_dh_for(&self, _role: DhRole, choice: &DHChoice) -> Option<Box<dyn Dh>> {
+        self.resolve_dh(choice)
+    }
+
     /// Provide an implementation of the Hash trait for the given HashChoice or None if unavailable.
     fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<dyn Hash>>;
 
@@ -74,6 +95,12 @@ impl CryptoResolver for FallbackResolver {
         self.preferred.resolve_dh(choice).or_else(|| self.fallback.resolve_dh(choice))
     }
 
+    fn resolve_dh_for(&self, role: DhRole, choice: &DHChoice) -> Option<Box<dyn Dh>> {
+        self.preferred
+            .resolve_dh_for(role, choice)
+            .or_else(|| self.fallback.resolve_dh_for(role, choice))
+    }
+
     fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<dyn Hash>> {
         self.preferred.resolve_hash(choice).or_else(|| self.fallback.resolve_hash(choice))
     }
@@ -87,3 +114,146 @@ impl CryptoResolver for FallbackResolver {
         self.preferred.resolve_kem(choice).or_else(|| self.fallback.resolve_kem(choice))
     }
 }
+
+/// Wraps another resolver to hand out [`Dh25519Elligator`] for Curve25519 ephemeral keys,
+/// so that they go over the wire as Elligator2 representatives. Everything else, including
+/// static keys, comes from the wrapped resolver.
+///
+/// Both peers need it, as the protocol name doesn't change.
+#[cfg(feature = "elligator2")]
+pub struct ElligatorResolver {
+    inner: BoxedCryptoResolver,
+}
+
+#[cfg(feature = "elligator2")]
+impl ElligatorResolver {
+    /// Create a new `ElligatorResolver` around `inner`.
+    pub fn new(inner: BoxedCryptoResolver) -> Self {
+        Self { inner }
+    }
+}
+
+#[cfg(feature = "elligator2")]
+impl CryptoResolver for ElligatorResolver {
+    fn resolve_rng(&self) -> Option<Box<dyn Random>> {
+        self.inner.resolve_rng()
+    }
+
+    fn resolve_dh(&self, choice: &DHChoice) -> Option<Box<dyn Dh>> {
+        self.inner.resolve_dh(choice)
+    }
+
+    fn resolve_dh_for(&self, role: DhRole, choice: &DHChoice) -> Option<Box<dyn Dh>> {
+        match (role, choice) {
+            (DhRole::Ephemeral, DHChoice::Curve25519) => Some(Box::<Dh25519Elligator>::default()),
+            _ => self.inner.resolve_dh_for(role, choice),
+        }
+    }
+
+    fn resolve_hash(&self, choice: &HashChoice) -> Option<Box<dyn Hash>> {
+        self.inner.resolve_hash(choice)
+    }
+
+    fn resolve_cipher(&self, choice: &CipherChoice) -> Option<Box<dyn Cipher>> {
+        self.inner.resolve_cipher(choice)
+    }
+
+    #[cfg(feature = "hfs")]
+    fn resolve_kem(&self, choice: &KemChoice) -> Option<Box<dyn Kem>> {
+        self.inner.resolve_kem(choice)
+    }
+}
+
+/// A resolver that only provides a deterministic, seeded RNG, for reproducible tests.
+///
+/// Every call to `resolve_rng()` starts a fresh stream from the same seed, so two sessions
+/// built from the same seed will generate the same keys. Give each peer its own seed.
+///
+/// **This RNG is not cryptographically secure. Never use it outside of tests.**
+#[cfg(feature = "test-utils")]
+pub struct SeededResolver {
+    seed: [u8; 32],
+}
+
+#[cfg(feature = "test-utils")]
+impl SeededResolver {
+    /// Create a new `SeededResolver` whose RNG streams all start from `seed`.
+    pub fn new(seed: [u8; 32]) -> Self {
+        Self { seed }
+    }
+}
+
+#[cfg(feature = "test-utils")]
+impl CryptoResolver for SeededResolver {
+    fn resolve_rng(&self) -> Option<Box<dyn Random>> {
+        Some(Box::new(SeededRng::new(&self.seed)))
+    }
+
+    fn resolve_dh(&self, _choice: &DHChoice) -> Option<Box<dyn Dh>> {
+        None
+    }
+
+    fn resolve_hash(&self, _choice: &HashChoice) -> Option<Box<dyn Hash>> {
+        None
+    }
+
+    fn resolve_cipher(&self, _choice: &CipherChoice) -> Option<Box<dyn Cipher>> {
+        None
+    }
+}
+
+/// Counter-based generator: each output is a SplitMix64 finalization of the folded seed
+/// mixed with an incrementing counter.
+#[cfg(feature = "test-utils")]
+struct SeededRng {
+    key:     u64,
+    counter: u64,
+}
+
+#[cfg(feature = "test-utils")]
+impl SeededRng {
+    const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+    fn new(seed: &[u8; 32]) -> Self {
+        let mut key = 0u64;
+        for chunk in seed.chunks_exact(8) {
+            let mut word = [0u8; 8];
+            word.copy_from_slice(chunk);
+            key = Self::mix(key ^ u64::from_le_bytes(word));
+        }
+        Self { key, counter: 0 }
+    }
+
+    fn mix(mut z: u64) -> u64 {
+        z = z.wrapping_add(Self::GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(feature = "test-utils")]
+impl rand_core::RngCore for SeededRng {
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.counter += 1;
+        Self::mix(self.key ^ self.counter.wrapping_mul(Self::GAMMA))
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        rand_core::impls::fill_bytes_via_next(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "test-utils")]
+impl rand_core::CryptoRng for SeededRng {}
+#[cfg(feature = "test-utils")]
+impl Random for SeededRng {}
@@ -39,6 +39,8 @@ impl CryptoResolver for RingResolver {
             CipherChoice::ChaChaPoly => Some(Box::new(CipherChaChaPoly::default())),
             #[cfg(feature = "xchachapoly")]
             CipherChoice::XChaChaPoly => None,
+            #[cfg(feature = "aes128gcm")]
+            CipherChoice::AES128GCM => None,
         }
     }
 }
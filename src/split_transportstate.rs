@@ -0,0 +1,186 @@
+use alloc::{vec, vec::Vec};
+use crate::{
+    cipherstate::CipherState,
+    constants::TAGLEN,
+    error::{Error, StateProblem},
+    transportstate::TransportState,
+};
+use core::fmt;
+
+/// The sending half of a [`TransportState`], from [`TransportState::split()`].
+///
+/// [`TransportState`]: crate::TransportState
+/// [`TransportState::split()`]: crate::TransportState::split
+pub struct TransportSender {
+    cipher:  CipherState,
+    oneway:  bool,
+    max_len: usize,
+}
+
+/// The receiving half of a [`TransportState`], from [`TransportState::split()`].
+///
+/// [`TransportState`]: crate::TransportState
+/// [`TransportState::split()`]: crate::TransportState::split
+pub struct TransportReceiver {
+    cipher: CipherState,
+    oneway: bool,
+}
+
+impl TransportSender {
+    pub(crate) fn new(cipher: CipherState, oneway: bool, max_len: usize) -> Self {
+        Self { cipher, oneway, max_len }
+    }
+
+    /// Encrypt `payload` into `message` like [`TransportState::write_message()`].
+    ///
+    /// Returns the size of the message.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::State` if the pattern is one-way and this is the responder, and
+    /// in `Error::Input` if `message` is too small or the message would exceed the max message
+    /// length.
+    ///
+    /// [`TransportState::write_message()`]: crate::TransportState::write_message
+    pub fn write_message(&mut self, payload: &[u8], message: &mut [u8]) -> Result<usize, Error> {
+        self.write_message_with_ad(&[], payload, message)
+    }
+
+    /// Like [`write_message()`], but also authenticates `ad` as the AEAD's associated data.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`write_message()`].
+    ///
+    /// [`write_message()`]: #method.write_message
+    pub fn write_message_with_ad(
+        &mut self,
+        ad: &[u8],
+        payload: &[u8],
+        message: &mut [u8],
+    ) -> Result<usize, Error> {
+        if self.oneway {
+            bail!(StateProblem::OneWay);
+        } else if payload.len() + TAGLEN > self.max_len || payload.len() + TAGLEN > message.len() {
+            bail!(Error::Input);
+        }
+        self.cipher.encrypt_ad(ad, payload, message)
+    }
+
+    /// Like [`write_message()`], but allocates and returns the message.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`write_message()`].
+    ///
+    /// [`write_message()`]: #method.write_message
+    pub fn write_message_vec(&mut self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut message = vec![0u8; TransportState::max_ciphertext_len(payload.len())];
+        let len = self.write_message(payload, &mut message)?;
+        message.truncate(len);
+        Ok(message)
+    }
+
+    /// Generates a new key for the outgoing direction, like
+    /// [`TransportState::rekey_outgoing()`].
+    ///
+    /// [`TransportState::rekey_outgoing()`]: crate::TransportState::rekey_outgoing
+    pub fn rekey(&mut self) {
+        self.cipher.rekey()
+    }
+
+    /// Get the forthcoming outbound nonce value.
+    pub fn nonce(&self) -> u64 {
+        self.cipher.nonce()
+    }
+}
+
+impl TransportReceiver {
+    pub(crate) fn new(cipher: CipherState, oneway: bool) -> Self {
+        Self { cipher, oneway }
+    }
+
+    /// Decrypt `message` into `payload` like [`TransportState::read_message()`].
+    ///
+    /// Returns the size of the payload.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::State` if the pattern is one-way and this is the initiator,
+    /// `Error::Decrypt` if the authentication tag didn't verify, and `Error::Input` if
+    /// `payload` is too small.
+    ///
+    /// [`TransportState::read_message()`]: crate::TransportState::read_message
+    pub fn read_message(&mut self, message: &[u8], payload: &mut [u8]) -> Result<usize, Error> {
+        self.read_message_with_ad(&[], message, payload)
+    }
+
+    /// Like [`read_message()`], but also checks that the message was written with the same
+    /// `ad`.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Decrypt` if `ad` differs from the writer's, and otherwise fails
+    /// like [`read_message()`].
+    ///
+    /// [`read_message()`]: #method.read_message
+    pub fn read_message_with_ad(
+        &mut self,
+        ad: &[u8],
+        message: &[u8],
+        payload: &mut [u8],
+    ) -> Result<usize, Error> {
+        if self.oneway {
+            bail!(StateProblem::OneWay);
+        }
+        self.cipher.decrypt_ad(ad, message, payload)
+    }
+
+    /// Like [`read_message()`], but allocates and returns the payload.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`read_message()`].
+    ///
+    /// [`read_message()`]: #method.read_message
+    pub fn read_message_vec(&mut self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut payload = vec![0u8; TransportState::max_plaintext_len(message.len())];
+        let len = self.read_message(message, &mut payload)?;
+        payload.truncate(len);
+        Ok(payload)
+    }
+
+    /// Generates a new key for the incoming direction, like
+    /// [`TransportState::rekey_incoming()`].
+    ///
+    /// [`TransportState::rekey_incoming()`]: crate::TransportState::rekey_incoming
+    pub fn rekey(&mut self) {
+        self.cipher.rekey()
+    }
+
+    /// Get the forthcoming inbound nonce value.
+    pub fn nonce(&self) -> u64 {
+        self.cipher.nonce()
+    }
+
+    /// Sets the inbound nonce, e.g. for lossy transports.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::State` if `nonce` is the reserved value 2^64-1.
+    pub fn set_nonce(&mut self, nonce: u64) -> Result<(), Error> {
+        self.cipher.set_nonce(nonce)
+    }
+}
+
+impl fmt::Debug for TransportSender {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("TransportSender").field("cipher", &self.cipher).finish()
+    }
+}
+
+impl fmt::Debug for TransportReceiver {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("TransportReceiver").field("cipher", &self.cipher).finish()
+    }
+}
@@ -1,12 +1,12 @@
 use crate::{
     cipherstate::StatelessCipherStates,
-    constants::{MAXDHLEN, MAXMSGLEN, TAGLEN},
+    constants::{MAXDHLEN, TAGLEN},
     error::{Error, StateProblem},
     handshakestate::HandshakeState,
-    params::HandshakePattern,
-    utils::Toggle,
+    params::NoiseParams,
+    utils::{redacted, Toggle},
 };
-use std::{convert::TryFrom, fmt};
+use core::{convert::TryFrom, fmt};
 
 /// A state machine encompassing the transport phase of a Noise session, using the two
 /// `CipherState`s (for sending and receiving) that were spawned from the `SymmetricState`'s
@@ -15,10 +15,11 @@ use std::{convert::TryFrom, fmt};
 /// See: http://noiseprotocol.org/noise.html#the-handshakestate-object
 pub struct StatelessTransportState {
     cipherstates: StatelessCipherStates,
-    pattern:      HandshakePattern,
+    params:       NoiseParams,
     dh_len:       usize,
     rs:           Toggle<[u8; MAXDHLEN]>,
     initiator:    bool,
+    max_len:      usize,
 }
 
 impl StatelessTransportState {
@@ -28,10 +29,15 @@ impl StatelessTransportState {
         }
 
         let dh_len = handshake.dh_len();
-        let HandshakeState { cipherstates, params, rs, initiator, .. } = handshake;
-        let pattern = params.handshake.pattern;
+        let HandshakeState { cipherstates, params, rs, initiator, max_len, .. } = handshake;
 
-        Ok(Self { cipherstates: cipherstates.into(), pattern, dh_len, rs, initiator })
+        Ok(Self { cipherstates: cipherstates.into(), params, dh_len, rs, initiator, max_len })
+    }
+
+    /// Get the Noise parameters negotiated for this session, e.g. to find out which DH, cipher,
+    /// and hash functions are in use.
+    pub fn get_params(&self) -> &NoiseParams {
+        &self.params
     }
 
     /// Get the remote party's static public key, if available.
@@ -40,6 +46,10 @@ impl StatelessTransportState {
     /// doesn't necessitate a remote static key, *or* if the remote
     /// static key is not yet known (as can be the case in the `XX`
     /// pattern, for example).
+    ///
+    /// See [`HandshakeState::get_remote_static()`] on comparing it.
+    ///
+    /// [`HandshakeState::get_remote_static()`]: crate::HandshakeState::get_remote_static
     pub fn get_remote_static(&self) -> Option<&[u8]> {
         self.rs.get().map(|rs| &rs[..self.dh_len])
     }
@@ -52,21 +62,44 @@ impl StatelessTransportState {
     /// # Errors
     ///
     /// Will result in `Error::Input` if the size of the output exceeds the max message
-    /// length in the Noise Protocol (65535 bytes).
+    /// length in the Noise Protocol (65535 bytes, unless changed with
+    /// [`Builder::max_message_len()`]), and in `Error::State` if `nonce` is the reserved
+    /// value 2^64-1.
+    ///
+    /// [`Builder::max_message_len()`]: crate::Builder::max_message_len
     pub fn write_message(
         &self,
         nonce: u64,
         payload: &[u8],
         message: &mut [u8],
     ) -> Result<usize, Error> {
-        if !self.initiator && self.pattern.is_oneway() {
+        self.write_message_with_ad(nonce, &[], payload, message)
+    }
+
+    /// Like [`write_message()`], but also authenticates `ad` as the AEAD's associated data.
+    /// The reader must pass the same `ad` to [`read_message_with_ad()`].
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`write_message()`].
+    ///
+    /// [`write_message()`]: #method.write_message
+    /// [`read_message_with_ad()`]: #method.read_message_with_ad
+    pub fn write_message_with_ad(
+        &self,
+        nonce: u64,
+        ad: &[u8],
+        payload: &[u8],
+        message: &mut [u8],
+    ) -> Result<usize, Error> {
+        if !self.initiator && self.params.handshake.pattern.is_oneway() {
             bail!(StateProblem::OneWay);
-        } else if payload.len() + TAGLEN > MAXMSGLEN || payload.len() + TAGLEN > message.len() {
+        } else if payload.len() + TAGLEN > self.max_len || payload.len() + TAGLEN > message.len() {
             bail!(Error::Input);
         }
 
         let cipher = if self.initiator { &self.cipherstates.0 } else { &self.cipherstates.1 };
-        cipher.encrypt(nonce, payload, message)
+        cipher.encrypt_ad(nonce, ad, payload, message)
     }
 
     /// Reads a noise message from `input`
@@ -75,23 +108,42 @@ impl StatelessTransportState {
     ///
     /// # Errors
     ///
-    /// Will result in `Error::Decrypt` if the contents couldn't be decrypted and/or the
-    /// authentication tag didn't verify.
+    /// Will result in `Error::Decrypt` if the authentication tag didn't verify, i.e. the
+    /// message was corrupted or tampered with, and in `Error::Input` if `payload` is too
+    /// small to hold the decrypted contents. On `Error::Decrypt`, the part of `payload` the
+    /// message would have filled is zeroed. Will result in `Error::State` if `nonce` is the
+    /// reserved value 2^64-1.
+    pub fn read_message(
+        &self,
+        nonce: u64,
+        payload: &[u8],
+        message: &mut [u8],
+    ) -> Result<usize, Error> {
+        self.read_message_with_ad(nonce, &[], payload, message)
+    }
+
+    /// Like [`read_message()`], but also checks that the message was written with
+    /// [`write_message_with_ad()`] and the same `ad`.
     ///
-    /// # Panics
+    /// # Errors
     ///
-    /// This function will panic if there is no key.
-    pub fn read_message(
+    /// Will result in `Error::Decrypt` if `ad` differs from the writer's, and otherwise fails
+    /// like [`read_message()`].
+    ///
+    /// [`read_message()`]: #method.read_message
+    /// [`write_message_with_ad()`]: #method.write_message_with_ad
+    pub fn read_message_with_ad(
         &self,
         nonce: u64,
+        ad: &[u8],
         payload: &[u8],
         message: &mut [u8],
     ) -> Result<usize, Error> {
-        if self.initiator && self.pattern.is_oneway() {
+        if self.initiator && self.params.handshake.pattern.is_oneway() {
             bail!(StateProblem::OneWay);
         }
         let cipher = if self.initiator { &self.cipherstates.1 } else { &self.cipherstates.0 };
-        cipher.decrypt(nonce, payload, message).map_err(|_| Error::Decrypt)
+        cipher.decrypt_ad(nonce, ad, payload, message)
     }
 
     /// Generates a new key for the egress symmetric cipher according to Section 4.2
@@ -146,7 +198,12 @@ impl StatelessTransportState {
 
 impl fmt::Debug for StatelessTransportState {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt.debug_struct("StatelessTransportState").finish()
+        fmt.debug_struct("StatelessTransportState")
+            .field("params", &self.params.name)
+            .field("initiator", &self.initiator)
+            .field("cipherstates", &self.cipherstates)
+            .field("rs", &redacted(self.rs.is_on()))
+            .finish()
     }
 }
 
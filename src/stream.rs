@@ -0,0 +1,255 @@
+//! A framed, encrypted stream over tokio's `AsyncRead` + `AsyncWrite`.
+
+use alloc::{vec, vec::Vec};
+use crate::{
+    constants::{LENLEN, MAXMSGLEN, TAGLEN},
+    HandshakeState, TransportState,
+};
+use core::{
+    fmt,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// Wraps an `AsyncRead + AsyncWrite` byte stream and a [`TransportState`], and is itself
+/// `AsyncRead + AsyncWrite`.
+///
+/// Each Noise message is sent as a frame prefixed with its length as a 2-byte big-endian
+/// integer. Writes are split into frames of at most [`TransportState::MAX_CHUNK_LEN`] bytes
+/// of plaintext, and are buffered until flushed.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use tokio::io::{AsyncRead, AsyncWrite};
+/// # async fn try_main<S: AsyncRead + AsyncWrite + Unpin>(socket: S) -> std::io::Result<()> {
+/// use tokio::io::AsyncWriteExt;
+///
+/// let noise = snow::Builder::new("Noise_NN_25519_ChaChaPoly_BLAKE2s".parse().unwrap())
+///     .build_initiator()
+///     .unwrap();
+/// let mut stream = snow::NoiseStream::handshake(socket, noise).await?;
+/// stream.write_all(b"hack the planet").await?;
+/// stream.flush().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct NoiseStream<S> {
+    inner:       S,
+    transport:   TransportState,
+    read_frame:  Vec<u8>,
+    read_filled: usize,
+    plaintext:   Vec<u8>,
+    plain_pos:   usize,
+    plain_len:   usize,
+    write_frame: Vec<u8>,
+    write_pos:   usize,
+    write_len:   usize,
+}
+
+impl<S> NoiseStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Wrap a stream whose handshake has already been completed.
+    pub fn new(inner: S, transport: TransportState) -> Self {
+        NoiseStream {
+            inner,
+            transport,
+            read_frame: vec![0u8; LENLEN + MAXMSGLEN],
+            read_filled: 0,
+            plaintext: vec![0u8; MAXMSGLEN],
+            plain_pos: 0,
+            plain_len: 0,
+            write_frame: vec![0u8; LENLEN + MAXMSGLEN],
+            write_pos: 0,
+            write_len: 0,
+        }
+    }
+
+    /// Drive `handshake` to completion over `inner` using empty payloads, then wrap it.
+    ///
+    /// # Errors
+    ///
+    /// Will result in an `io::Error` if the underlying stream fails or closes early, and in
+    /// one of kind `InvalidData` if the handshake itself fails.
+    pub async fn handshake(mut inner: S, mut handshake: HandshakeState) -> io::Result<Self> {
+        let mut frame = vec![0u8; LENLEN + MAXMSGLEN];
+        let mut payload = vec![0u8; MAXMSGLEN];
+        while !handshake.is_handshake_finished() {
+            if handshake.is_my_turn() {
+                let len = handshake.write_message(&[], &mut frame[LENLEN..]).map_err(invalid)?;
+                frame[..LENLEN].copy_from_slice(&(len as u16).to_be_bytes());
+                inner.write_all(&frame[..LENLEN + len]).await?;
+                inner.flush().await?;
+            } else {
+                inner.read_exact(&mut frame[..LENLEN]).await?;
+                let len = u16::from_be_bytes([frame[0], frame[1]]) as usize;
+                inner.read_exact(&mut frame[LENLEN..LENLEN + len]).await?;
+                let message = &frame[LENLEN..LENLEN + len];
+                handshake.read_message(message, &mut payload).map_err(invalid)?;
+            }
+        }
+        Ok(Self::new(inner, handshake.into_transport_mode().map_err(invalid)?))
+    }
+
+    /// Gets a reference to the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Gets a mutable reference to the underlying stream. Reading from or writing to it
+    /// directly will corrupt the framing.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Gets a reference to the [`TransportState`], e.g. to check the remote static key.
+    pub fn transport(&self) -> &TransportState {
+        &self.transport
+    }
+
+    /// Unwraps the underlying stream and the [`TransportState`]. Any buffered data is lost.
+    pub fn into_inner(self) -> (S, TransportState) {
+        (self.inner, self.transport)
+    }
+
+    /// Writes out as much of the pending encrypted frame as the underlying stream accepts.
+    fn poll_write_frame(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_len {
+            let frame = &self.write_frame[self.write_pos..self.write_len];
+            match Pin::new(&mut self.inner).poll_write(cx, frame) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => self.write_pos += n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        self.write_pos = 0;
+        self.write_len = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Reads until a whole frame is buffered. Resolves to `false` on a clean end of stream.
+    fn poll_read_frame(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        loop {
+            let needed = if self.read_filled < LENLEN {
+                LENLEN
+            } else {
+                let len = u16::from_be_bytes([self.read_frame[0], self.read_frame[1]]) as usize;
+                if len < TAGLEN {
+                    return Poll::Ready(Err(io::ErrorKind::InvalidData.into()));
+                }
+                LENLEN + len
+            };
+            if self.read_filled == needed && needed > LENLEN {
+                return Poll::Ready(Ok(true));
+            }
+
+            let mut buf = ReadBuf::new(&mut self.read_frame[self.read_filled..needed]);
+            match Pin::new(&mut self.inner).poll_read(cx, &mut buf) {
+                Poll::Ready(Ok(())) if buf.filled().is_empty() => {
+                    return if self.read_filled == 0 {
+                        Poll::Ready(Ok(false))
+                    } else {
+                        Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+                    };
+                },
+                Poll::Ready(Ok(())) => self.read_filled += buf.filled().len(),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S> AsyncRead for NoiseStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        while this.plain_pos == this.plain_len {
+            match this.poll_read_frame(cx) {
+                Poll::Ready(Ok(true)) => {},
+                Poll::Ready(Ok(false)) => return Poll::Ready(Ok(())),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+            let message = &this.read_frame[LENLEN..this.read_filled];
+            let result = this.transport.read_message(message, &mut this.plaintext);
+            // The frame is consumed either way, so a failed one isn't decrypted again on the
+            // next read.
+            this.read_filled = 0;
+            this.plain_pos = 0;
+            this.plain_len = result.map_err(invalid)?;
+        }
+
+        let len = buf.remaining().min(this.plain_len - this.plain_pos);
+        buf.put_slice(&this.plaintext[this.plain_pos..this.plain_pos + len]);
+        this.plain_pos += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for NoiseStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match this.poll_write_frame(cx) {
+            Poll::Ready(Ok(())) => {},
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let (consumed, len) =
+            this.transport.write_chunk(buf, &mut this.write_frame[LENLEN..]).map_err(invalid)?;
+        this.write_frame[..LENLEN].copy_from_slice(&(len as u16).to_be_bytes());
+        this.write_len = LENLEN + len;
+        Poll::Ready(Ok(consumed))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_write_frame(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_write_frame(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
+
+impl<S> fmt::Debug for NoiseStream<S> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("NoiseStream").finish()
+    }
+}
+
+fn invalid(e: crate::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
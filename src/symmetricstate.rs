@@ -1,11 +1,14 @@
+use alloc::boxed::Box;
 use crate::{
     cipherstate::CipherState,
     constants::{CIPHERKEYLEN, MAXHASHLEN},
     error::Error,
     types::Hash,
 };
+use zeroize::{Zeroize, Zeroizing};
 
-#[derive(Copy, Clone)]
+/// Not `Copy`, so that every copy, like the one in a `Checkpoint`, is wiped when dropped.
+#[derive(Clone)]
 pub(crate) struct SymmetricStateData {
     h:       [u8; MAXHASHLEN],
     ck:      [u8; MAXHASHLEN],
@@ -22,6 +25,21 @@ impl Default for SymmetricStateData {
     }
 }
 
+impl Drop for SymmetricStateData {
+    fn drop(&mut self) {
+        self.ck.zeroize();
+    }
+}
+
+/// What `restore()` rolls back to. Tokens can rekey or advance the handshake cipher before a
+/// message fails, so its key and nonce are saved along with the hashes.
+#[derive(Clone)]
+pub(crate) struct Checkpoint {
+    inner: SymmetricStateData,
+    key:   Option<Zeroizing<[u8; CIPHERKEYLEN]>>,
+    n:     u64,
+}
+
 pub(crate) struct SymmetricState {
     cipherstate: CipherState,
     hasher:      Box<dyn Hash>,
@@ -45,7 +63,7 @@ impl SymmetricState {
         self.inner.has_key = false;
     }
 
-    pub fn mix_key(&mut self, data: &[u8]) {
+    pub fn mix_key(&mut self, data: &[u8]) -> Result<(), Error> {
         let hash_len = self.hasher.hash_len();
         let mut hkdf_output = ([0u8; MAXHASHLEN], [0u8; MAXHASHLEN]);
         self.hasher.hkdf(
@@ -57,8 +75,25 @@ impl SymmetricState {
             &mut [],
         );
         copy_slices!(&hkdf_output.0, &mut self.inner.ck);
-        self.cipherstate.set(&hkdf_output.1[..CIPHERKEYLEN], 0);
+        self.cipherstate.set(&hkdf_output.1[..CIPHERKEYLEN], 0)?;
         self.inner.has_key = true;
+        Ok(())
+    }
+
+    /// Mix `context` into the chaining key only, for `Builder::hkdf_context()`. Unlike
+    /// `mix_key()`, this sets no cipher key, so messages keep their shape.
+    pub fn mix_context(&mut self, context: &[u8]) {
+        let hash_len = self.hasher.hash_len();
+        let mut hkdf_output = [0u8; MAXHASHLEN];
+        self.hasher.hkdf(
+            &self.inner.ck[..hash_len],
+            context,
+            1,
+            &mut hkdf_output,
+            &mut [],
+            &mut [],
+        );
+        copy_slices!(&hkdf_output, &mut self.inner.ck);
     }
 
     pub fn mix_hash(&mut self, data: &[u8]) {
@@ -69,7 +104,21 @@ impl SymmetricState {
         self.hasher.result(&mut self.inner.h);
     }
 
-    pub fn mix_key_and_hash(&mut self, data: &[u8]) {
+    /// Like `mix_hash()` over whatever `input` feeds to the hasher, e.g. data in several parts
+    /// or from a stream, without joining it first.
+    pub fn mix_hash_with(
+        &mut self,
+        input: impl FnOnce(&mut dyn Hash) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let hash_len = self.hasher.hash_len();
+        self.hasher.reset();
+        self.hasher.input(&self.inner.h[..hash_len]);
+        input(&mut *self.hasher)?;
+        self.hasher.result(&mut self.inner.h);
+        Ok(())
+    }
+
+    pub fn mix_key_and_hash(&mut self, data: &[u8]) -> Result<(), Error> {
         let hash_len = self.hasher.hash_len();
         let mut hkdf_output = ([0u8; MAXHASHLEN], [0u8; MAXHASHLEN], [0u8; MAXHASHLEN]);
         self.hasher.hkdf(
@@ -82,7 +131,7 @@ impl SymmetricState {
         );
         copy_slices!(&hkdf_output.0, &mut self.inner.ck);
         self.mix_hash(&hkdf_output.1[..hash_len]);
-        self.cipherstate.set(&hkdf_output.2[..CIPHERKEYLEN], 0);
+        self.cipherstate.set(&hkdf_output.2[..CIPHERKEYLEN], 0)
     }
 
     pub fn has_key(&self) -> bool {
@@ -106,13 +155,13 @@ impl SymmetricState {
         Ok(output_len)
     }
 
-    pub fn decrypt_and_mix_hash(&mut self, data: &[u8], out: &mut [u8]) -> Result<usize, ()> {
+    pub fn decrypt_and_mix_hash(&mut self, data: &[u8], out: &mut [u8]) -> Result<usize, Error> {
         let hash_len = self.hasher.hash_len();
         let payload_len = if self.inner.has_key {
             self.cipherstate.decrypt_ad(&self.inner.h[..hash_len], data, out)?
         } else {
             if out.len() < data.len() {
-                return Err(());
+                bail!(Error::Input);
             }
             copy_slices!(data, out);
             data.len()
@@ -121,24 +170,43 @@ impl SymmetricState {
         Ok(payload_len)
     }
 
-    pub fn split(&mut self, child1: &mut CipherState, child2: &mut CipherState) {
+    pub fn split(
+        &mut self,
+        child1: &mut CipherState,
+        child2: &mut CipherState,
+    ) -> Result<(), Error> {
         let mut hkdf_output = ([0u8; MAXHASHLEN], [0u8; MAXHASHLEN]);
         self.split_raw(&mut hkdf_output.0, &mut hkdf_output.1);
-        child1.set(&hkdf_output.0[..CIPHERKEYLEN], 0);
-        child2.set(&hkdf_output.1[..CIPHERKEYLEN], 0);
+        child1.set(&hkdf_output.0[..CIPHERKEYLEN], 0)?;
+        child2.set(&hkdf_output.1[..CIPHERKEYLEN], 0)
+    }
+
+    pub fn split_raw(&mut self, out1: &mut [u8], out2: &mut [u8]) {
+        let hash_len = self.hasher.hash_len();
+        self.hasher.hkdf(&self.inner.ck[..hash_len], &[0u8; 0], 2, out1, out2, &mut []);
     }
 
-    pub fn split_raw(&mut self, mut out1: &mut [u8], mut out2: &mut [u8]) {
+    /// The secret behind `TransportState::export_keying_material()`: `HKDF(ck, h)`, which
+    /// unlike the split's `HKDF(ck, zerolen)` can't collide with a cipher key.
+    pub fn exporter_secret(&mut self, out: &mut [u8]) {
         let hash_len = self.hasher.hash_len();
-        self.hasher.hkdf(&self.inner.ck[..hash_len], &[0u8; 0], 2, &mut out1, &mut out2, &mut []);
+        let h = self.inner.h;
+        self.hasher.hkdf(&self.inner.ck[..hash_len], &h[..hash_len], 1, out, &mut [], &mut []);
     }
 
-    pub(crate) fn checkpoint(&mut self) -> SymmetricStateData {
-        self.inner
+    pub(crate) fn checkpoint(&mut self) -> Checkpoint {
+        Checkpoint {
+            inner: self.inner.clone(),
+            key:   self.cipherstate.key().ok().map(|key| Zeroizing::new(*key)),
+            n:     self.cipherstate.nonce(),
+        }
     }
 
-    pub(crate) fn restore(&mut self, checkpoint: SymmetricStateData) {
-        self.inner = checkpoint;
+    pub(crate) fn restore(&mut self, checkpoint: Checkpoint) {
+        self.inner = checkpoint.inner;
+        if let Some(key) = &checkpoint.key {
+            self.cipherstate.restore(&key[..], checkpoint.n);
+        }
     }
 
     pub fn handshake_hash(&self) -> &[u8] {
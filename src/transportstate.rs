@@ -1,12 +1,23 @@
+use alloc::{boxed::Box, vec, vec::Vec};
 use crate::{
-    cipherstate::CipherStates,
-    constants::{MAXDHLEN, MAXMSGLEN, TAGLEN},
+    cipherstate::{CipherState, CipherStates},
+    constants::{CIPHERKEYLEN, MAXDHLEN, MAXHASHLEN, MAXMSGLEN, PSKLEN, TAGLEN},
     error::{Error, StateProblem},
     handshakestate::HandshakeState,
-    params::HandshakePattern,
-    utils::Toggle,
+    params::NoiseParams,
+    split_transportstate::{TransportReceiver, TransportSender},
+    types::Hash,
+    utils::{read_framed, redacted, write_framed, Toggle},
 };
-use std::{convert::TryFrom, fmt};
+use core::{
+    convert::{TryFrom, TryInto},
+    fmt,
+};
+#[cfg(feature = "std")]
+use std::io::IoSlice;
+#[cfg(feature = "std")]
+use zeroize::Zeroize;
+use zeroize::Zeroizing;
 
 /// A state machine encompassing the transport phase of a Noise session, using the two
 /// `CipherState`s (for sending and receiving) that were spawned from the `SymmetricState`'s
@@ -14,24 +25,213 @@ use std::{convert::TryFrom, fmt};
 ///
 /// Also see: [the relevant Noise spec section](http://noiseprotocol.org/noise.html#the-handshakestate-object).
 pub struct TransportState {
-    cipherstates: CipherStates,
-    pattern:      HandshakePattern,
-    dh_len:       usize,
-    rs:           Toggle<[u8; MAXDHLEN]>,
-    initiator:    bool,
+    cipherstates:   CipherStates,
+    hasher:         Box<dyn Hash>,
+    params:         NoiseParams,
+    dh_len:         usize,
+    rs:             Toggle<[u8; MAXDHLEN]>,
+    handshake_hash: Vec<u8>,
+    initiator:      bool,
+    max_len:        usize,
+    exporter:       Option<Zeroizing<[u8; MAXHASHLEN]>>,
+    rekey_at:       Option<u64>,
 }
 
+/// Version byte of the format written by [`TransportState::save_transport_state()`].
+const SAVED_STATE_VERSION: u8 = 1;
+/// Version, flags, and each side's key and nonce, followed by the handshake hash length.
+const SAVED_STATE_HEADER_LEN: usize = 2 + 2 * (CIPHERKEYLEN + 8) + 1;
+const SAVED_STATE_INITIATOR: u8 = 0b01;
+const SAVED_STATE_REMOTE_STATIC: u8 = 0b10;
+
 impl TransportState {
-    pub(crate) fn new(handshake: HandshakeState) -> Result<Self, Error> {
+    /// The largest plaintext chunk that fits in a single Noise message, i.e. 65535 bytes minus
+    /// the authentication tag. See [`TransportState::write_chunk()`].
+    pub const MAX_CHUNK_LEN: usize = MAXMSGLEN - TAGLEN;
+
+    /// The size of the payload in a transport message of `ciphertext_len` bytes, i.e. the
+    /// length minus the authentication tag. Also applies to [`StatelessTransportState`].
+    ///
+    /// [`StatelessTransportState`]: crate::StatelessTransportState
+    pub const fn max_plaintext_len(ciphertext_len: usize) -> usize {
+        ciphertext_len.saturating_sub(TAGLEN)
+    }
+
+    /// The size of the transport message carrying a payload of `plaintext_len` bytes, i.e. the
+    /// length plus the authentication tag. Also applies to [`StatelessTransportState`].
+    ///
+    /// [`StatelessTransportState`]: crate::StatelessTransportState
+    pub const fn max_ciphertext_len(plaintext_len: usize) -> usize {
+        plaintext_len.saturating_add(TAGLEN)
+    }
+
+    pub(crate) fn new(mut handshake: HandshakeState) -> Result<Self, Error> {
         if !handshake.is_handshake_finished() {
             bail!(StateProblem::HandshakeNotFinished);
         }
 
         let dh_len = handshake.dh_len();
-        let HandshakeState { cipherstates, params, rs, initiator, .. } = handshake;
-        let pattern = params.handshake.pattern;
+        let handshake_hash = handshake.get_handshake_hash().to_vec();
+        let mut exporter = Zeroizing::new([0u8; MAXHASHLEN]);
+        handshake.symmetricstate.exporter_secret(&mut exporter[..]);
+        let HandshakeState {
+            cipherstates, transport_hasher, params, rs, initiator, max_len, ..
+        } = handshake;
+
+        Ok(TransportState {
+            cipherstates,
+            hasher: transport_hasher,
+            params,
+            dh_len,
+            rs,
+            handshake_hash,
+            initiator,
+            max_len,
+            exporter: Some(exporter),
+            rekey_at: None,
+        })
+    }
+
+    /// Rebuilds a state saved by [`save_transport_state()`] around fresh, unkeyed
+    /// `cipherstates`. See [`Builder::restore_transport_state()`].
+    ///
+    /// [`save_transport_state()`]: #method.save_transport_state
+    /// [`Builder::restore_transport_state()`]: crate::Builder::restore_transport_state
+    pub(crate) fn restore(
+        state: &[u8],
+        mut cipherstates: CipherStates,
+        hasher: Box<dyn Hash>,
+        params: NoiseParams,
+        dh_len: usize,
+        max_len: usize,
+    ) -> Result<Self, Error> {
+        let hash_len = hasher.hash_len();
+        if state.len() < SAVED_STATE_HEADER_LEN || state[0] != SAVED_STATE_VERSION {
+            bail!(Error::Input);
+        }
+        let flags = state[1];
+        let has_rs = flags & SAVED_STATE_REMOTE_STATIC != 0;
+        let hash_end = SAVED_STATE_HEADER_LEN + hash_len;
+        let rs_len = if has_rs { dh_len } else { 0 };
+        if flags & !(SAVED_STATE_INITIATOR | SAVED_STATE_REMOTE_STATIC) != 0
+            || usize::from(state[SAVED_STATE_HEADER_LEN - 1]) != hash_len
+            || state.len() != hash_end + rs_len
+        {
+            bail!(Error::Input);
+        }
+
+        let restore_cipher = |cipher: &mut CipherState, saved: &[u8]| {
+            let (key, nonce) = saved.split_at(CIPHERKEYLEN);
+            cipher.set(key, 0)?;
+            cipher.set_nonce(u64::from_be_bytes(nonce.try_into().unwrap()))
+        };
+        let side_len = CIPHERKEYLEN + 8;
+        restore_cipher(&mut cipherstates.0, &state[2..2 + side_len])?;
+        restore_cipher(&mut cipherstates.1, &state[2 + side_len..2 + 2 * side_len])?;
+
+        let mut rs_buf = [0u8; MAXDHLEN];
+        let rs = if has_rs {
+            rs_buf[..dh_len].copy_from_slice(&state[hash_end..]);
+            Toggle::on(rs_buf)
+        } else {
+            Toggle::off(rs_buf)
+        };
+
+        Ok(TransportState {
+            cipherstates,
+            hasher,
+            params,
+            dh_len,
+            rs,
+            handshake_hash: state[SAVED_STATE_HEADER_LEN..hash_end].to_vec(),
+            initiator: flags & SAVED_STATE_INITIATOR != 0,
+            max_len,
+            exporter: None,
+            rekey_at: None,
+        })
+    }
+
+    /// Serialize the keys and nonces of both directions, along with the handshake hash,
+    /// role, and remote static key, so that the session can be picked up again with
+    /// [`Builder::restore_transport_state()`], e.g. in another process.
+    ///
+    /// The output holds the session's cipher keys, so protect it like a private key, and
+    /// don't keep using this state once it has been restored elsewhere: reusing a nonce
+    /// breaks the cipher's security.
+    ///
+    /// The format (integers are big-endian) is:
+    ///
+    /// | Bytes    | Contents                                                |
+    /// |----------|---------------------------------------------------------|
+    /// | 1        | Format version, currently 1                             |
+    /// | 1        | Flags: `0x01` if initiator, `0x02` if remote static key |
+    /// | 32       | Initiator-egress cipher key                             |
+    /// | 8        | Initiator-egress nonce                                  |
+    /// | 32       | Responder-egress cipher key                             |
+    /// | 8        | Responder-egress nonce                                  |
+    /// | 1        | Handshake hash length (HASHLEN)                         |
+    /// | HASHLEN  | Handshake hash                                          |
+    /// | DHLEN    | Remote static public key, if flagged                    |
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::State` if a cipher has no key, or with
+    /// `StateProblem::UnknownKey` if a custom [`Cipher`] overriding [`Cipher::rekey()`]
+    /// rekeyed it to a key other than the spec's.
+    ///
+    /// [`Builder::restore_transport_state()`]: crate::Builder::restore_transport_state
+    /// [`Cipher`]: crate::types::Cipher
+    /// [`Cipher::rekey()`]: crate::types::Cipher::rekey
+    pub fn save_transport_state(&self) -> Result<Vec<u8>, Error> {
+        let rs = self.get_remote_static();
+        let mut state = Vec::with_capacity(
+            SAVED_STATE_HEADER_LEN + self.handshake_hash.len() + rs.map_or(0, <[u8]>::len),
+        );
+        let mut flags = 0;
+        if self.initiator {
+            flags |= SAVED_STATE_INITIATOR;
+        }
+        if rs.is_some() {
+            flags |= SAVED_STATE_REMOTE_STATIC;
+        }
+        state.extend_from_slice(&[SAVED_STATE_VERSION, flags]);
+        for cipher in &[&self.cipherstates.0, &self.cipherstates.1] {
+            state.extend_from_slice(cipher.key()?);
+            state.extend_from_slice(&cipher.nonce().to_be_bytes());
+        }
+        state.push(self.handshake_hash.len() as u8);
+        state.extend_from_slice(&self.handshake_hash);
+        if let Some(rs) = rs {
+            state.extend_from_slice(rs);
+        }
+        Ok(state)
+    }
 
-        Ok(TransportState { cipherstates, pattern, dh_len, rs, initiator })
+    /// **Dangerous: exposes the session's secret keys.** Get the current transport keys,
+    /// `(initiator to responder, responder to initiator)`, i.e. `k1` and `k2` of the spec's
+    /// `Split()` or what rekeying has turned them into since.
+    ///
+    /// Whoever holds these can read and forge every message of the session. This only exists
+    /// to compare derived keys against reference vectors or another implementation when
+    /// debugging interop, and requires the `test-utils` feature. **Never use it, or enable
+    /// that feature, in production.**
+    #[cfg(feature = "test-utils")]
+    pub fn transport_keys_for_testing(&self) -> (Vec<u8>, Vec<u8>) {
+        let key = |cipher: &CipherState| cipher.key().map_or_else(|_| Vec::new(), |key| key.to_vec());
+        (key(&self.cipherstates.0), key(&self.cipherstates.1))
+    }
+
+    /// Get the Noise parameters negotiated for this session, e.g. to find out which DH, cipher,
+    /// and hash functions are in use.
+    pub fn get_params(&self) -> &NoiseParams {
+        &self.params
+    }
+
+    /// Get the handshake hash of the handshake that established this session.
+    ///
+    /// Returns a slice of length `Hasher.hash_len()` (i.e. HASHLEN for the chosen Hash function).
+    pub fn get_handshake_hash(&self) -> &[u8] {
+        &self.handshake_hash
     }
 
     /// Get the remote party's static public key, if available.
@@ -40,6 +240,10 @@ impl TransportState {
     /// doesn't necessitate a remote static key, *or* if the remote
     /// static key is not yet known (as can be the case in the `XX`
     /// pattern, for example).
+    ///
+    /// See [`HandshakeState::get_remote_static()`] on comparing it.
+    ///
+    /// [`HandshakeState::get_remote_static()`]: crate::HandshakeState::get_remote_static
     pub fn get_remote_static(&self) -> Option<&[u8]> {
         self.rs.get().map(|rs| &rs[..self.dh_len])
     }
@@ -52,38 +256,285 @@ impl TransportState {
     /// # Errors
     ///
     /// Will result in `Error::Input` if the size of the output exceeds the max message
-    /// length in the Noise Protocol (65535 bytes).
+    /// length in the Noise Protocol (65535 bytes, unless changed with
+    /// [`Builder::max_message_len()`]).
+    ///
+    /// Will result in `Error::State` (`StateProblem::Exhausted`) once the sending nonce has
+    /// reached the reserved value 2^64-1: the session can't send anymore. See
+    /// [`set_rekey_threshold()`] to be warned well before.
+    ///
+    /// [`Builder::max_message_len()`]: crate::Builder::max_message_len
+    /// [`set_rekey_threshold()`]: #method.set_rekey_threshold
     pub fn write_message(&mut self, payload: &[u8], message: &mut [u8]) -> Result<usize, Error> {
-        if !self.initiator && self.pattern.is_oneway() {
-            bail!(StateProblem::OneWay);
-        } else if payload.len() + TAGLEN > MAXMSGLEN || payload.len() + TAGLEN > message.len() {
-            bail!(Error::Input);
-        }
+        self.write_message_with_ad(&[], payload, message)
+    }
+
+    /// Like [`write_message()`], but also authenticates `ad` as the AEAD's associated data.
+    /// The reader must pass the same `ad` to [`read_message_with_ad()`].
+    ///
+    /// `ad` isn't sent: use it to bind context both sides already know, such as a message type
+    /// from the framing layer.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`write_message()`].
+    ///
+    /// [`write_message()`]: #method.write_message
+    /// [`read_message_with_ad()`]: #method.read_message_with_ad
+    pub fn write_message_with_ad(
+        &mut self,
+        ad: &[u8],
+        payload: &[u8],
+        message: &mut [u8],
+    ) -> Result<usize, Error> {
+        self.check_write(payload.len(), message.len())?;
+        let cipher =
+            if self.initiator { &mut self.cipherstates.0 } else { &mut self.cipherstates.1 };
+        cipher.encrypt_ad(ad, payload, message)
+    }
 
+    /// Like [`write_message()`], but encrypts the payload in the first `payload_len` bytes of
+    /// `buf` in place and appends the tag, so no separate output buffer is needed. `buf` must
+    /// have room for the `TAGLEN` (16) bytes of tag after the payload.
+    ///
+    /// This saves the memory of a second buffer. It doesn't make encryption noticeably faster:
+    /// the copy it avoids is cheap next to the cipher itself (see the `transport/write` and
+    /// `transport/write_in_place` benchmarks). Resolvers whose [`Cipher`] doesn't override
+    /// [`Cipher::encrypt_in_place()`] still copy the payload internally.
+    ///
+    /// Returns the size of the message, i.e. `payload_len + 16`.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Input` if `buf` has no room for the tag or the message would
+    /// exceed the max message length (see [`write_message()`]).
+    ///
+    /// [`write_message()`]: #method.write_message
+    /// [`Cipher`]: crate::types::Cipher
+    /// [`Cipher::encrypt_in_place()`]: crate::types::Cipher::encrypt_in_place
+    pub fn write_message_in_place(
+        &mut self,
+        buf: &mut [u8],
+        payload_len: usize,
+    ) -> Result<usize, Error> {
+        self.check_write(payload_len, buf.len())?;
         let cipher =
             if self.initiator { &mut self.cipherstates.0 } else { &mut self.cipherstates.1 };
-        Ok(cipher.encrypt(payload, message)?)
+        cipher.encrypt_in_place_ad(&[], buf, payload_len)
+    }
+
+    /// Like [`write_message()`], but with the payload in several slices, e.g. a header and a
+    /// body. The message is exactly the one [`write_message()`] makes of the slices joined
+    /// together, encrypted in a single AEAD operation: the slices are gathered into `message`
+    /// and encrypted there in place, like with [`write_message_in_place()`].
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`write_message()`]. If encryption fails after gathering, the gathered
+    /// plaintext is wiped from `message`.
+    ///
+    /// [`write_message()`]: #method.write_message
+    /// [`write_message_in_place()`]: #method.write_message_in_place
+    #[cfg(feature = "std")]
+    pub fn write_message_vectored(
+        &mut self,
+        payload: &[IoSlice<'_>],
+        message: &mut [u8],
+    ) -> Result<usize, Error> {
+        let payload_len = payload.iter().map(|slice| slice.len()).sum();
+        self.check_write(payload_len, message.len())?;
+        let mut offset = 0;
+        for slice in payload {
+            message[offset..offset + slice.len()].copy_from_slice(slice);
+            offset += slice.len();
+        }
+        self.write_message_in_place(message, payload_len)
+            .inspect_err(|_| message[..payload_len].zeroize())
+    }
+
+    /// Checks that a payload of `payload_len` bytes can be written into `out_len` bytes.
+    fn check_write(&self, payload_len: usize, out_len: usize) -> Result<(), Error> {
+        if !self.initiator && self.params.handshake.pattern.is_oneway() {
+            bail!(StateProblem::OneWay);
+        }
+        // `payload_len` isn't the length of a slice here, so it can be anything.
+        let message_len = payload_len.checked_add(TAGLEN).ok_or(Error::Input)?;
+        if message_len > self.max_len || message_len > out_len {
+            bail!(Error::Input);
+        }
+        Ok(())
     }
 
     /// Reads a noise message from `input`
     ///
     /// Returns the size of the payload written to `payload`.
     ///
+    /// `message` must be exactly one message, as its length is taken to be the ciphertext's:
+    /// e.g. slice a datagram received into a larger buffer to the received length. `payload`
+    /// may be larger than needed.
+    ///
     /// # Errors
     ///
-    /// Will result in `Error::Decrypt` if the contents couldn't be decrypted and/or the
-    /// authentication tag didn't verify.
+    /// Will result in `Error::Decrypt` if the authentication tag didn't verify, i.e. the
+    /// message was corrupted or tampered with, and in `Error::Input` if `payload` is too
+    /// small to hold the decrypted contents. On `Error::Decrypt`, the part of `payload` the
+    /// message would have filled is zeroed.
     ///
-    /// # Panics
+    /// Will result in `Error::State` (`StateProblem::Exhausted`) once the receiving nonce has
+    /// reached the reserved value 2^64-1. See [`set_rekey_threshold()`].
     ///
-    /// This function will panic if there is no key, or if there is a nonce overflow.
+    /// [`set_rekey_threshold()`]: #method.set_rekey_threshold
     pub fn read_message(&mut self, message: &[u8], payload: &mut [u8]) -> Result<usize, Error> {
-        if self.initiator && self.pattern.is_oneway() {
+        self.read_message_with_ad(&[], message, payload)
+    }
+
+    /// Like [`read_message()`], but also checks that the message was written with
+    /// [`write_message_with_ad()`] and the same `ad`.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Decrypt` if `ad` differs from the writer's, and otherwise fails
+    /// like [`read_message()`].
+    ///
+    /// [`read_message()`]: #method.read_message
+    /// [`write_message_with_ad()`]: #method.write_message_with_ad
+    pub fn read_message_with_ad(
+        &mut self,
+        ad: &[u8],
+        message: &[u8],
+        payload: &mut [u8],
+    ) -> Result<usize, Error> {
+        if self.initiator && self.params.handshake.pattern.is_oneway() {
             bail!(StateProblem::OneWay);
         }
         let cipher =
             if self.initiator { &mut self.cipherstates.1 } else { &mut self.cipherstates.0 };
-        cipher.decrypt(message, payload).map_err(|_| Error::Decrypt)
+        cipher.decrypt_ad(ad, message, payload)
+    }
+
+    /// Like [`write_message()`], but allocates and returns the message.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`write_message()`].
+    ///
+    /// [`write_message()`]: #method.write_message
+    pub fn write_message_vec(&mut self, payload: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut message = vec![0u8; Self::max_ciphertext_len(payload.len())];
+        let len = self.write_message(payload, &mut message)?;
+        message.truncate(len);
+        Ok(message)
+    }
+
+    /// Like [`read_message()`], but allocates and returns the payload.
+    ///
+    /// # Errors
+    ///
+    /// Fails like [`read_message()`].
+    ///
+    /// [`read_message()`]: #method.read_message
+    pub fn read_message_vec(&mut self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut payload = vec![0u8; Self::max_plaintext_len(message.len())];
+        let len = self.read_message(message, &mut payload)?;
+        payload.truncate(len);
+        Ok(payload)
+    }
+
+    /// Encrypts `payload` and appends it to `out` as a frame: the length of the message as a
+    /// 2-byte big-endian integer, followed by the message itself.
+    ///
+    /// Returns the number of bytes appended. On error, `out` is left as it was.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Input` if `payload` is longer than [`MAX_CHUNK_LEN`], as the
+    /// message would not fit in a frame.
+    ///
+    /// [`MAX_CHUNK_LEN`]: #associatedconstant.MAX_CHUNK_LEN
+    pub fn write_message_framed(
+        &mut self,
+        payload: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<usize, Error> {
+        if payload.len() > Self::MAX_CHUNK_LEN {
+            bail!(Error::Input);
+        }
+        write_framed(out, payload.len() + TAGLEN, |message| self.write_message(payload, message))
+    }
+
+    /// Reads one frame written by [`write_message_framed()`] from the front of `input`.
+    ///
+    /// Returns the bytes of `input` following the frame, and the decrypted payload.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Input` if `input` is shorter than the frame claims to be, and
+    /// in `Error::Decrypt` if the contents couldn't be decrypted and/or the authentication
+    /// tag didn't verify.
+    ///
+    /// [`write_message_framed()`]: #method.write_message_framed
+    pub fn read_message_framed<'a>(
+        &mut self,
+        input: &'a [u8],
+    ) -> Result<(&'a [u8], Vec<u8>), Error> {
+        read_framed(input, |message, payload| {
+            if message.len() < TAGLEN {
+                bail!(Error::Decrypt);
+            }
+            self.read_message(message, payload)
+        })
+    }
+
+    /// Encrypts the next chunk of a stream that may be far larger than a single Noise message.
+    ///
+    /// Up to [`MAX_CHUNK_LEN`] bytes are taken from the front of `input` and written to
+    /// `message` as one self-contained Noise message with its own tag and the next nonce.
+    /// Returns `(consumed, len)`: the number of `input` bytes consumed, and the size of the
+    /// message written. Call it repeatedly on `&input[consumed..]` until `input` is empty.
+    ///
+    /// Since every chunk is authenticated under its own nonce, the receiver will fail to
+    /// decrypt any chunk that was dropped, reordered or tampered with. The receiver reassembles
+    /// the stream by passing each message, in order, to [`read_chunk()`].
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Input` if `message` can't hold the encrypted chunk.
+    ///
+    /// [`MAX_CHUNK_LEN`]: #associatedconstant.MAX_CHUNK_LEN
+    /// [`read_chunk()`]: #method.read_chunk
+    pub fn write_chunk(
+        &mut self,
+        input: &[u8],
+        message: &mut [u8],
+    ) -> Result<(usize, usize), Error> {
+        let consumed = input.len().min(Self::MAX_CHUNK_LEN);
+        let len = self.write_message(&input[..consumed], message)?;
+        Ok((consumed, len))
+    }
+
+    /// Decrypts a message written by [`write_chunk()`] and appends its plaintext to `output`.
+    ///
+    /// Returns the number of bytes appended. On error, `output` is left as it was.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Decrypt` if the contents couldn't be decrypted and/or the
+    /// authentication tag didn't verify, e.g. because a chunk was lost or reordered.
+    ///
+    /// [`write_chunk()`]: #method.write_chunk
+    pub fn read_chunk(&mut self, message: &[u8], output: &mut Vec<u8>) -> Result<usize, Error> {
+        if message.len() < TAGLEN {
+            bail!(Error::Decrypt);
+        }
+        let start = output.len();
+        output.resize(start + Self::max_plaintext_len(message.len()), 0);
+        match self.read_message(message, &mut output[start..]) {
+            Ok(len) => Ok(len),
+            Err(e) => {
+                output.truncate(start);
+                Err(e)
+            },
+        }
     }
 
     /// Generates a new key for the egress symmetric cipher according to Section 4.2
@@ -110,6 +561,25 @@ impl TransportState {
         }
     }
 
+    /// Keep the incoming key replaced by the next rekey for a grace period, so that messages
+    /// the peer sent before it rekeyed still decrypt if they arrive after
+    /// [`rekey_incoming()`] (or a manual rekey of the incoming key).
+    ///
+    /// A message that fails to decrypt with the current key is then tried with the previous
+    /// one. The previous key is dropped as soon as a message decrypts with the current key,
+    /// since nothing sent under the old key can follow it, or at the rekey after. The setting
+    /// stays with the [`TransportReceiver`] after [`split()`].
+    ///
+    /// [`rekey_incoming()`]: #method.rekey_incoming
+    /// [`split()`]: #method.split
+    pub fn enable_rekey_grace(&mut self) {
+        if self.initiator {
+            self.cipherstates.1.keep_previous_key()
+        } else {
+            self.cipherstates.0.keep_previous_key()
+        }
+    }
+
     /// Set a new key for the one or both of the initiator-egress and responder-egress symmetric ciphers.
     pub fn rekey_manually(&mut self, initiator: Option<&[u8]>, responder: Option<&[u8]>) {
         if let Some(key) = initiator {
@@ -130,20 +600,113 @@ impl TransportState {
         self.cipherstates.rekey_responder_manually(key)
     }
 
+    /// Hash `data` with the hash function negotiated for this session, e.g. to derive an
+    /// identifier that matches the handshake's algorithm.
+    ///
+    /// Returns `HASHLEN` bytes, i.e. the [`Hash::hash_len()`] of the chosen hash.
+    ///
+    /// [`Hash::hash_len()`]: crate::types::Hash::hash_len
+    pub fn hash(&mut self, data: &[u8]) -> Vec<u8> {
+        hash_once(&mut *self.hasher, data)
+    }
+
+    /// Derive `out.len()` bytes of key material bound to this session, e.g. for a separate
+    /// subchannel, in the spirit of TLS exporters. Both peers get the same bytes for the same
+    /// `label` and `context`, and different labels or contexts give unrelated bytes.
+    ///
+    /// The bytes are `HKDF-Expand(secret, len(label) || label || context, out.len())` (RFC
+    /// 5869, with the chosen hash and a 2-byte big-endian length), where `secret` is
+    /// `HKDF(ck, h)` of the finished handshake. They don't depend on the cipher keys, so rekeying
+    /// or [`mix_psk()`](#method.mix_psk) doesn't change them.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Input` if `label` is longer than 65535 bytes or `out` is longer
+    /// than 255 times `HASHLEN`, and in `Error::State` if the state was restored with
+    /// [`Builder::restore_transport_state()`], which doesn't keep the chaining key.
+    ///
+    /// [`Builder::restore_transport_state()`]: crate::Builder::restore_transport_state
+    pub fn export_keying_material(
+        &mut self,
+        label: &[u8],
+        context: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), Error> {
+        let secret = self.exporter.as_ref().ok_or(StateProblem::MissingKeyMaterial)?;
+        let hash_len = self.hasher.hash_len();
+        if label.len() > usize::from(u16::MAX) || out.len() > 255 * hash_len {
+            bail!(Error::Input);
+        }
+
+        // T(i) = HMAC(secret, T(i - 1) || info || i), with T(0) empty.
+        let mut input = Zeroizing::new(Vec::with_capacity(hash_len + 2 + label.len() + 1));
+        let mut block = Zeroizing::new([0u8; MAXHASHLEN]);
+        for (i, chunk) in out.chunks_mut(hash_len).enumerate() {
+            input.truncate(0);
+            if i > 0 {
+                input.extend_from_slice(&block[..hash_len]);
+            }
+            input.extend_from_slice(&(label.len() as u16).to_be_bytes());
+            input.extend_from_slice(label);
+            input.extend_from_slice(context);
+            input.push(i as u8 + 1);
+            self.hasher.hmac(&secret[..hash_len], &input, &mut block[..]);
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    /// Mix `psk` into the keys of both directions, e.g. when a PSK is refreshed out of band,
+    /// without a new handshake. Unlike a rekey, this brings in new secret material.
+    ///
+    /// Each key `k` becomes the first `CIPHERKEYLEN` bytes of `HKDF(k, psk)` with the chosen
+    /// hash. Nonces are left as they are. Both peers must mix the same PSK between the same
+    /// two messages, or everything after it will fail to decrypt.
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::Input` if `psk` isn't `PSKLEN` (32) bytes, and with
+    /// `StateProblem::UnknownKey` after a custom rekey, see [`save_transport_state()`].
+    ///
+    /// [`save_transport_state()`]: #method.save_transport_state
+    pub fn mix_psk(&mut self, psk: &[u8]) -> Result<(), Error> {
+        if psk.len() != PSKLEN {
+            bail!(Error::Input);
+        }
+        // Either both directions change or neither does.
+        self.cipherstates.0.key()?;
+        self.cipherstates.1.key()?;
+        mix_psk_into(&mut *self.hasher, &mut self.cipherstates.0, psk)?;
+        mix_psk_into(&mut *self.hasher, &mut self.cipherstates.1, psk)
+    }
+
     /// Sets the *receiving* CipherState's nonce. Useful for using noise on lossy transports.
-    pub fn set_receiving_nonce(&mut self, nonce: u64) {
+    ///
+    /// # Errors
+    ///
+    /// Will result in `Error::State` if `nonce` is the reserved value 2^64-1.
+    pub fn set_receiving_nonce(&mut self, nonce: u64) -> Result<(), Error> {
         if self.initiator {
-            self.cipherstates.1.set_nonce(nonce);
+            self.cipherstates.1.set_nonce(nonce)
         } else {
-            self.cipherstates.0.set_nonce(nonce);
+            self.cipherstates.0.set_nonce(nonce)
         }
     }
 
-    /// Get the forthcoming inbound nonce value.
+    /// Sets the *sending* CipherState's nonce, e.g. to resume a session at an agreed position.
     ///
     /// # Errors
     ///
-    /// Will result in `Error::State` if not in transport mode.
+    /// Will result in `Error::State` if `nonce` is the reserved value 2^64-1.
+    pub fn set_sending_nonce(&mut self, nonce: u64) -> Result<(), Error> {
+        if self.initiator {
+            self.cipherstates.0.set_nonce(nonce)
+        } else {
+            self.cipherstates.1.set_nonce(nonce)
+        }
+    }
+
+    /// Get the forthcoming inbound nonce value.
     pub fn receiving_nonce(&self) -> u64 {
         if self.initiator {
             self.cipherstates.1.nonce()
@@ -153,10 +716,6 @@ impl TransportState {
     }
 
     /// Get the forthcoming outbound nonce value.
-    ///
-    /// # Errors
-    ///
-    /// Will result in `Error::State` if not in transport mode.
     pub fn sending_nonce(&self) -> u64 {
         if self.initiator {
             self.cipherstates.0.nonce()
@@ -165,15 +724,81 @@ impl TransportState {
         }
     }
 
+    /// Set a nonce value at which [`at_rekey_threshold()`] starts returning `true`, as a warning
+    /// well ahead of the hard limit where messages fail with `StateProblem::Exhausted`.
+    ///
+    /// Note that rekeying changes the keys but keeps counting nonces, so near the end of the
+    /// nonce space a new handshake is needed to keep talking.
+    ///
+    /// [`at_rekey_threshold()`]: #method.at_rekey_threshold
+    pub fn set_rekey_threshold(&mut self, threshold: u64) {
+        self.rekey_at = Some(threshold);
+    }
+
+    /// Check whether the sending or receiving nonce has reached the threshold set with
+    /// [`set_rekey_threshold()`]. Always `false` if no threshold was set.
+    ///
+    /// [`set_rekey_threshold()`]: #method.set_rekey_threshold
+    pub fn at_rekey_threshold(&self) -> bool {
+        self.rekey_at
+            .is_some_and(|at| self.sending_nonce() >= at || self.receiving_nonce() >= at)
+    }
+
     /// Check if this session was started with the "initiator" role.
     pub fn is_initiator(&self) -> bool {
         self.initiator
     }
+
+    /// Split the session into its sending and receiving halves, each owning its direction's
+    /// key and nonce, so that a write loop and a read loop can run on different threads or
+    /// tasks without a lock.
+    ///
+    /// The halves can't be joined again, and lose what needs both directions, e.g.
+    /// [`mix_psk()`](#method.mix_psk) and [`save_transport_state()`]. The framed and chunked
+    /// helpers aren't available either.
+    ///
+    /// [`save_transport_state()`]: #method.save_transport_state
+    pub fn split(self) -> (TransportSender, TransportReceiver) {
+        let CipherStates(initiator_cipher, responder_cipher) = self.cipherstates;
+        let oneway = self.params.handshake.pattern.is_oneway();
+        let (sending, receiving) = if self.initiator {
+            (initiator_cipher, responder_cipher)
+        } else {
+            (responder_cipher, initiator_cipher)
+        };
+        (
+            TransportSender::new(sending, oneway && !self.initiator, self.max_len),
+            TransportReceiver::new(receiving, oneway && self.initiator),
+        )
+    }
+}
+
+/// `HASH(data)` with a hasher that may have been used before.
+pub(crate) fn hash_once(hasher: &mut dyn Hash, data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0u8; MAXHASHLEN];
+    hasher.reset();
+    hasher.input(data);
+    hasher.result(&mut out);
+    out.truncate(hasher.hash_len());
+    out
+}
+
+fn mix_psk_into(hasher: &mut dyn Hash, cipher: &mut CipherState, psk: &[u8]) -> Result<(), Error> {
+    let key = Zeroizing::new(*cipher.key()?);
+    let mut new_key = Zeroizing::new([0u8; MAXHASHLEN]);
+    hasher.hkdf(&key[..], psk, 1, &mut new_key[..], &mut [], &mut []);
+    cipher.rekey_manually(&new_key[..CIPHERKEYLEN]);
+    Ok(())
 }
 
 impl fmt::Debug for TransportState {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt.debug_struct("TransportState").finish()
+        fmt.debug_struct("TransportState")
+            .field("params", &self.params.name)
+            .field("initiator", &self.initiator)
+            .field("cipherstates", &self.cipherstates)
+            .field("rs", &redacted(self.rs.is_on()))
+            .finish()
     }
 }
 
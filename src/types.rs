@@ -2,6 +2,7 @@
 
 use crate::constants::{CIPHERKEYLEN, MAXBLOCKLEN, MAXHASHLEN, TAGLEN};
 use rand_core::{CryptoRng, RngCore};
+use zeroize::Zeroizing;
 
 /// CSPRNG operations
 pub trait Random: CryptoRng + RngCore + Send + Sync {}
@@ -20,6 +21,22 @@ pub trait Dh: Send + Sync {
     /// Set the private key
     fn set(&mut self, privkey: &[u8]);
 
+    /// Whether `set()` can take `privkey`. The builder checks the keys it's given with this
+    /// first, and fails with `InitStage::ValidatePrivateKey` on those it can't.
+    ///
+    /// The default implementation accepts every key.
+    fn accepts_privkey(&self, _privkey: &[u8]) -> bool {
+        true
+    }
+
+    /// Set the private key along with its already-known public key, sparing the work of
+    /// deriving it. `pubkey` must be the public half of `privkey`.
+    ///
+    /// The default implementation ignores `pubkey` and calls `set()`.
+    fn set_keypair(&mut self, privkey: &[u8], _pubkey: &[u8]) {
+        self.set(privkey)
+    }
+
     /// Generate a new private key
     fn generate(&mut self, rng: &mut dyn Random);
 
@@ -30,7 +47,20 @@ pub trait Dh: Send + Sync {
     fn privkey(&self) -> &[u8];
 
     /// Calculate a Diffie-Hellman exchange.
+    #[allow(clippy::result_unit_err)]
     fn dh(&self, pubkey: &[u8], out: &mut [u8]) -> Result<(), ()>;
+
+    /// Decode the remote ephemeral public key, as sent by the same implementation's
+    /// `pubkey()`, into the form `dh()` takes. Only called on the ephemeral key's
+    /// implementation, for implementations that don't send plain public keys, like
+    /// `Dh25519Elligator` (with the `elligator2` feature).
+    ///
+    /// The default implementation copies `encoded` unchanged.
+    #[allow(clippy::result_unit_err)]
+    fn decode_pubkey(&self, encoded: &[u8], out: &mut [u8]) -> Result<(), ()> {
+        copy_slices!(encoded, out);
+        Ok(())
+    }
 }
 
 /// Cipher operations
@@ -39,12 +69,32 @@ pub trait Cipher: Send + Sync {
     fn name(&self) -> &'static str;
 
     /// Set the key
+    ///
+    /// `key` is `CIPHERKEYLEN` (32) bytes, as derived by the handshake. Ciphers with a
+    /// shorter key use its leading bytes.
     fn set(&mut self, key: &[u8]);
 
     /// Encrypt (with associated data) a given plaintext.
     fn encrypt(&self, nonce: u64, authtext: &[u8], plaintext: &[u8], out: &mut [u8]) -> usize;
 
+    /// Encrypt (with associated data) the plaintext in the first `plaintext_len` bytes of
+    /// `in_out` in place, writing the tag right after it. Returns the ciphertext length.
+    ///
+    /// The default implementation encrypts from a temporary copy of the plaintext; override it
+    /// if the cipher can work in place.
+    fn encrypt_in_place(
+        &self,
+        nonce: u64,
+        authtext: &[u8],
+        in_out: &mut [u8],
+        plaintext_len: usize,
+    ) -> usize {
+        let plaintext = Zeroizing::new(in_out[..plaintext_len].to_vec());
+        self.encrypt(nonce, authtext, &plaintext, in_out)
+    }
+
     /// Decrypt (with associated data) a given ciphertext.
+    #[allow(clippy::result_unit_err)]
     fn decrypt(
         &self,
         nonce: u64,
@@ -55,10 +105,14 @@ pub trait Cipher: Send + Sync {
 
     /// Rekey according to Section 4.2 of the Noise Specification, with a default
     /// implementation guaranteed to be secure for all ciphers.
+    ///
+    /// A session rekeyed by an override that derives another key keeps working, but can no
+    /// longer be saved with
+    /// [`save_transport_state()`](crate::TransportState::save_transport_state).
     fn rekey(&mut self) {
         let mut ciphertext = [0; CIPHERKEYLEN + TAGLEN];
         let ciphertext_len =
-            self.encrypt(u64::max_value(), &[], &[0; CIPHERKEYLEN], &mut ciphertext);
+            self.encrypt(u64::MAX, &[], &[0; CIPHERKEYLEN], &mut ciphertext);
         assert_eq!(ciphertext_len, ciphertext.len());
         self.set(&ciphertext[..CIPHERKEYLEN]);
     }
@@ -86,6 +140,9 @@ pub trait Hash: Send + Sync {
 
     /// Calculate HMAC, as specified in the Noise spec.
     ///
+    /// `key` can be at most `block_len()` bytes, since it's used as-is rather than hashed
+    /// first. Noise only ever passes keys of at most `hash_len()` bytes.
+    ///
     /// NOTE: This method clobbers the existing internal state
     fn hmac(&mut self, key: &[u8], data: &[u8], out: &mut [u8]) {
         assert!(key.len() <= self.block_len());
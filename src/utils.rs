@@ -1,4 +1,12 @@
-use std::ops::{Deref, DerefMut};
+use alloc::{vec, vec::Vec};
+use crate::{
+    constants::{LENLEN, MAXMSGLEN},
+    error::Error,
+};
+use core::{
+    fmt,
+    ops::{Deref, DerefMut},
+};
 
 /// Toggle is similar to Option, except that even in the Off/"None" case, there is still
 /// an owned allocated inner object. This is useful for holding onto pre-allocated objects
@@ -21,6 +29,10 @@ impl<T> Toggle<T> {
         self.on = true;
     }
 
+    pub fn disable(&mut self) {
+        self.on = false;
+    }
+
     pub fn is_on(&self) -> bool {
         self.on
     }
@@ -47,3 +59,64 @@ impl<T> DerefMut for Toggle<T> {
         &mut self.inner
     }
 }
+
+/// Stands in for key material in `Debug` output, so that a stray `{:?}` can't leak it.
+pub struct Redacted;
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.write_str("[REDACTED]")
+    }
+}
+
+/// `Some(Redacted)` if the key is `present`, to show whether it's set without showing it.
+pub fn redacted(present: bool) -> Option<Redacted> {
+    if present {
+        Some(Redacted)
+    } else {
+        None
+    }
+}
+
+/// Appends a 2-byte big-endian length prefix followed by the message produced by `write`,
+/// which is given room for a message of up to `max_len` bytes. Returns the number of bytes
+/// appended. On error, `out` is left as it was.
+pub fn write_framed<F>(out: &mut Vec<u8>, max_len: usize, write: F) -> Result<usize, Error>
+where
+    F: FnOnce(&mut [u8]) -> Result<usize, Error>,
+{
+    let start = out.len();
+    out.resize(start + LENLEN + max_len.min(MAXMSGLEN), 0);
+    match write(&mut out[start + LENLEN..]) {
+        Ok(len) => {
+            out[start..start + LENLEN].copy_from_slice(&(len as u16).to_be_bytes());
+            out.truncate(start + LENLEN + len);
+            Ok(LENLEN + len)
+        },
+        Err(e) => {
+            out.truncate(start);
+            Err(e)
+        },
+    }
+}
+
+/// Splits one length-prefixed frame off the front of `input`, passes its message to `read`
+/// along with a payload buffer, and returns the bytes following the frame along with the
+/// payload.
+pub fn read_framed<F>(input: &[u8], read: F) -> Result<(&[u8], Vec<u8>), Error>
+where
+    F: FnOnce(&[u8], &mut [u8]) -> Result<usize, Error>,
+{
+    if input.len() < LENLEN {
+        bail!(Error::Input);
+    }
+    let len = u16::from_be_bytes([input[0], input[1]]) as usize;
+    if input.len() < LENLEN + len {
+        bail!(Error::Input);
+    }
+    let (message, rest) = input[LENLEN..].split_at(len);
+    let mut payload = vec![0u8; len];
+    let payload_len = read(message, &mut payload)?;
+    payload.truncate(payload_len);
+    Ok((rest, payload))
+}
@@ -0,0 +1,69 @@
+#![cfg(all(feature = "default-resolver", feature = "std"))]
+
+//! Tests that count allocations. They live in their own binary so that the global allocator
+//! doesn't see the allocations of unrelated tests running in parallel.
+
+use snow::{params::NoiseParams, Builder};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+#[test]
+fn test_large_prologue_is_not_copied() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_SHA256".parse().unwrap();
+    let prologue = vec![7u8; 1 << 20];
+
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    let mut h_i = Builder::new(params.clone()).prologue(&prologue).build_initiator().unwrap();
+    let allocated = ALLOCATED.load(Ordering::SeqCst) - before;
+    assert!(allocated < prologue.len(), "building allocated {} bytes", allocated);
+
+    let (first, second) = prologue.split_at(1000);
+    let mut h_r = Builder::new(params)
+        .prologue_part(first)
+        .prologue_part(second)
+        .build_responder()
+        .unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let len = h_i.write_message(&[], &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_r.write_message(&[], &mut buffer_msg).unwrap();
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    assert_eq!(h_i.get_handshake_hash(), h_r.get_handshake_hash());
+}
+
+#[test]
+fn test_prologue_reader_is_not_buffered() {
+    let params: NoiseParams = "Noise_NN_25519_ChaChaPoly_SHA256".parse().unwrap();
+    let len = 1 << 20;
+    let prologue = std::io::Read::take(std::io::repeat(7), len);
+
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    let h_i = Builder::new(params.clone()).prologue_reader(prologue).build_initiator().unwrap();
+    let allocated = ALLOCATED.load(Ordering::SeqCst) - before;
+    assert!(allocated < len as usize, "building allocated {} bytes", allocated);
+
+    let h_r = Builder::new(params).prologue(&vec![7u8; len as usize]).build_responder().unwrap();
+    assert_eq!(h_i.get_handshake_hash(), h_r.get_handshake_hash());
+}
@@ -0,0 +1,136 @@
+#![cfg(all(feature = "interop-tests", feature = "default-resolver", feature = "std"))]
+
+//! A harness for handshakes and transport messages with a peer spawned as a subprocess that
+//! talks over its stdin and stdout.
+//!
+//! Set `SNOW_INTEROP_PEER` to the peer's executable to run these; without it they're
+//! skipped. The peer is started as `$SNOW_INTEROP_PEER <protocol name>` and plays the
+//! responder, with a static key of its own. Every message in either direction is preceded
+//! by its length as a 16-bit big-endian integer. After the handshake, the peer answers every
+//! transport message with one carrying the same payload, and exits once its stdin is
+//! closed.
+//!
+//! The only peer in this repository is `examples/interop_peer.rs`, which is built on snow:
+//! it checks the harness, not compatibility with anyone else. For that, point
+//! `SNOW_INTEROP_PEER` at a wrapper around another implementation. With noise-c, such a
+//! wrapper:
+//!
+//! 1. calls `noise_init()`, then `noise_handshakestate_new_by_name()` with `argv[1]` and
+//!    `NOISE_ROLE_RESPONDER`;
+//! 2. generates a static key with `noise_dhstate_generate_keypair()` on
+//!    `noise_handshakestate_get_local_keypair_dh()`, then calls
+//!    `noise_handshakestate_start()`;
+//! 3. loops on `noise_handshakestate_get_action()`: for `NOISE_ACTION_WRITE_MESSAGE` it
+//!    calls `noise_handshakestate_write_message()` with an empty payload and writes the
+//!    framed message to stdout, for `NOISE_ACTION_READ_MESSAGE` it reads a frame from stdin
+//!    and calls `noise_handshakestate_read_message()`, until `NOISE_ACTION_SPLIT`;
+//! 4. calls `noise_handshakestate_split()`, then for every frame on stdin calls
+//!    `noise_cipherstate_decrypt()` with the receiving cipher and writes the result of
+//!    `noise_cipherstate_encrypt()` with the sending one, exiting with 0 at end of input.
+
+use snow::{params::NoiseParams, Builder};
+use std::{
+    env,
+    ffi::OsString,
+    io::{Read, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+};
+
+struct Peer {
+    child:  Child,
+    stdin:  Option<ChildStdin>,
+    stdout: ChildStdout,
+}
+
+impl Peer {
+    fn spawn(command: &OsString, protocol_name: &str) -> Self {
+        let mut child = Command::new(command)
+            .arg(protocol_name)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap_or_else(|err| panic!("couldn't start {:?}: {}", command, err));
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take().unwrap();
+        Peer { child, stdin, stdout }
+    }
+
+    fn send(&mut self, message: &[u8]) {
+        let stdin = self.stdin.as_mut().unwrap();
+        stdin.write_all(&(message.len() as u16).to_be_bytes()).unwrap();
+        stdin.write_all(message).unwrap();
+        stdin.flush().unwrap();
+    }
+
+    fn recv(&mut self) -> Vec<u8> {
+        let mut len = [0u8; 2];
+        self.stdout.read_exact(&mut len).expect("peer hung up");
+        let mut message = vec![0u8; u16::from_be_bytes(len).into()];
+        self.stdout.read_exact(&mut message).expect("peer hung up");
+        message
+    }
+
+    /// Closes the peer's stdin and checks that it exits cleanly.
+    fn finish(mut self) {
+        self.stdin = None;
+        let status = self.child.wait().unwrap();
+        assert!(status.success(), "peer exited with {}", status);
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        // Don't leave the peer running when a test fails halfway.
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn run_xx(command: &OsString, protocol_name: &str) {
+    let params: NoiseParams = protocol_name.parse().unwrap();
+    let builder = Builder::new(params);
+    let static_key = builder.generate_keypair().unwrap().private;
+    let mut noise = builder.local_private_key(&static_key).build_initiator().unwrap();
+    let mut peer = Peer::spawn(command, protocol_name);
+
+    let mut buf = vec![0u8; 65535];
+    while !noise.is_handshake_finished() {
+        if noise.is_my_turn() {
+            let len = noise.write_message(&[], &mut buf).unwrap();
+            peer.send(&buf[..len]);
+        } else {
+            noise.read_message(&peer.recv(), &mut buf).unwrap();
+        }
+    }
+    assert!(noise.get_remote_static().is_some());
+
+    let mut noise = noise.into_transport_mode().unwrap();
+    let mut payload = vec![0u8; 65535];
+    for message in [&b"hack the planet"[..], b"", &[0x42; 65535 - 16]] {
+        let len = noise.write_message(message, &mut buf).unwrap();
+        peer.send(&buf[..len]);
+        let len = noise.read_message(&peer.recv(), &mut payload).unwrap();
+        assert_eq!(&payload[..len], message, "{}", protocol_name);
+    }
+    peer.finish();
+}
+
+#[test]
+fn test_interop_harness_xx() {
+    let command = match env::var_os("SNOW_INTEROP_PEER") {
+        Some(command) => command,
+        None => {
+            eprintln!("SNOW_INTEROP_PEER isn't set, skipping the interop tests");
+            return;
+        },
+    };
+
+    for protocol_name in [
+        "Noise_XX_25519_ChaChaPoly_BLAKE2s",
+        "Noise_XX_25519_AESGCM_SHA256",
+        "Noise_XX_25519_ChaChaPoly_SHA512",
+        "Noise_XX_25519_AESGCM_BLAKE2b",
+    ] {
+        run_xx(&command, protocol_name);
+    }
+}
@@ -0,0 +1,74 @@
+#![cfg(all(feature = "logging", feature = "default-resolver", feature = "std"))]
+
+use log::{LevelFilter, Log, Metadata, Record};
+use snow::{params::NoiseParams, Builder};
+use std::sync::Mutex;
+
+/// Collects every record, so a single test per binary can inspect them.
+struct TestLogger(Mutex<Vec<String>>);
+
+impl Log for TestLogger {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        self.0.lock().unwrap().push(format!("{} {}", record.level(), record.args()));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: TestLogger = TestLogger(Mutex::new(Vec::new()));
+
+#[test]
+fn test_handshake_logs_no_secrets() {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Trace);
+
+    let params: NoiseParams = "Noise_XXpsk3_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
+    let builder = Builder::new(params.clone());
+    let i_keys = builder.generate_keypair().unwrap();
+    let r_keys = builder.generate_keypair().unwrap();
+    let psk = builder.generate_keypair().unwrap().private;
+    let mut h_i = Builder::new(params.clone())
+        .local_private_key(&i_keys.private)
+        .psk(3, &psk)
+        .build_initiator()
+        .unwrap();
+    let mut h_r = Builder::new(params)
+        .local_private_key(&r_keys.private)
+        .psk(3, &psk)
+        .build_responder()
+        .unwrap();
+
+    let mut buffer_msg = [0u8; 200];
+    let mut buffer_out = [0u8; 200];
+    let payload = b"top secret payload";
+    let len = h_i.write_message(payload, &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_r.write_message(payload, &mut buffer_msg).unwrap();
+    let mut corrupted = buffer_msg[..len].to_vec();
+    corrupted[len - 1] ^= 1;
+    assert!(h_i.read_message(&corrupted, &mut buffer_out).is_err());
+    h_i.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let len = h_i.write_message(payload, &mut buffer_msg).unwrap();
+    h_r.read_message(&buffer_msg[..len], &mut buffer_out).unwrap();
+    let handshake_hash = h_i.get_handshake_hash().to_vec();
+
+    let records = LOGGER.0.lock().unwrap().join("\n");
+    assert!(records.contains("DEBUG wrote handshake message 1 of 3"), "{}", records);
+    assert!(records.contains("DEBUG failed to read handshake message 2"), "{}", records);
+    assert!(records.contains("TRACE reading token Psk(3)"), "{}", records);
+    assert!(records.contains("handshake finished as responder"), "{}", records);
+
+    for secret in [&i_keys.private[..], &r_keys.private, &psk, &handshake_hash, payload] {
+        // Neither hex nor the `Debug` form of a byte slice, nor any of its 8-byte windows.
+        for window in secret.windows(8) {
+            assert!(!records.contains(&hex::encode(window)), "{}", records);
+        }
+        let debug = format!("{:?}", &secret[..8]);
+        assert!(!records.contains(&debug[1..debug.len() - 1]), "{}", records);
+    }
+    assert!(!records.contains("top secret"), "{}", records);
+}
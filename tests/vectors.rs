@@ -139,6 +139,9 @@ struct TestVector {
     resp_remote_static: Option<HexBytes>,
 
     messages: Vec<TestMessage>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    handshake_hash: Option<HexBytes>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -201,6 +204,7 @@ fn confirm_message_vectors(
     mut init: HandshakeState,
     mut resp: HandshakeState,
     messages_vec: &Vec<TestMessage>,
+    handshake_hash: &Option<HexBytes>,
     is_oneway: bool,
 ) -> Result<(), String> {
     let (mut sendbuf, mut recvbuf) = ([0u8; 65535], [0u8; 65535]);
@@ -226,6 +230,20 @@ fn confirm_message_vectors(
         }
     }
 
+    if let Some(ref expected) = *handshake_hash {
+        for (role, actual) in
+            [("initiator", init.get_handshake_hash()), ("responder", resp.get_handshake_hash())]
+        {
+            if actual != &(*expected)[..] {
+                let mut s = String::new();
+                s.push_str(&format!("{} handshake hash\n", role));
+                s.push_str(&format!("expected:  {}\n", hex::encode(&**expected)));
+                s.push_str(&format!("actual:    {}", hex::encode(actual)));
+                return Err(s);
+            }
+        }
+    }
+
     let (mut init, mut resp) =
         (init.into_transport_mode().unwrap(), resp.into_transport_mode().unwrap());
     for (i, message) in messages {
@@ -259,16 +277,10 @@ fn test_vectors_from_json(json: &str) {
 
     let mut passes = 0;
     let mut fails = 0;
-    let mut ignored = 0;
 
     for vector in test_vectors.vectors {
         let params: NoiseParams = vector.protocol_name.parse().unwrap();
 
-        if params.dh == DHChoice::Ed448 {
-            ignored += 1;
-            continue;
-        }
-
         let (init, resp) = match build_session_pair(&vector) {
             Ok((init, resp)) => (init, resp),
             Err(s) => {
@@ -284,6 +296,7 @@ fn test_vectors_from_json(json: &str) {
             init,
             resp,
             &vector.messages,
+            &vector.handshake_hash,
             params.handshake.pattern.is_oneway(),
         ) {
             Ok(_) => {
@@ -299,7 +312,6 @@ fn test_vectors_from_json(json: &str) {
     }
 
     println!("\n{}/{} passed", passes, passes + fails);
-    println!("* ignored {} unsupported variants", ignored);
     if fails > 0 {
         panic!("at least one vector failed.");
     }
@@ -395,6 +407,7 @@ fn generate_vector(params: NoiseParams) -> TestVector {
         });
         let _ = init.read_message(&ibuf[..len], &mut obuf).unwrap();
     }
+    let handshake_hash = Some(init.get_handshake_hash().to_vec().into());
 
     let init_static = if params.handshake.pattern.needs_local_static_key(true) {
         Some(is.private.to_vec().into())
@@ -438,6 +451,7 @@ fn generate_vector(params: NoiseParams) -> TestVector {
         resp_ephemeral: Some(re.private.to_vec().into()),
         resp_remote_static,
         messages,
+        handshake_hash,
     }
 }
 